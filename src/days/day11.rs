@@ -1,12 +1,90 @@
 use std::fs;
+use std::str::FromStr;
 use itertools::iproduct;
 use rusttype::{Point, point};
 
+/*
+ Solution command:
+    cargo run -- day11 data/day11.txt [expansion factor] [galaxy number] [galaxy number]
+*/
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Universe {
+    pub galaxies: Vec<Point<isize>>,
+    pub empty_rows: Vec<isize>,
+    pub empty_cols: Vec<isize>,
+}
+
+impl FromStr for Universe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let image: Vec<Vec<_>> = s.lines()
+            .map(|l| l.chars().collect())
+            .collect();
+        if image.first().is_none_or(|row| row.is_empty()) {
+            return Err("Empty universe".to_string());
+        }
+        if let Some(y) = image.iter().position(|row| row.len() != image[0].len()) {
+            return Err(format!("Row {} has a different length from row 1", y + 1));
+        }
+
+        let empty_rows: Vec<_> = (0..image.len())
+                                    .filter(|r| image[*r].iter().all(|c| *c == '.'))
+                                    .map(|i| i as isize).collect();
+        let empty_cols: Vec<_> = (0..image[0].len())
+                                    .filter(|c| image.iter().all(|row| row[*c] == '.'))
+                                    .map(|i| i as isize).collect();
+
+        let galaxies: Vec<_> = iproduct!(0..image.len(), 0..image[0].len())
+                        .filter(|(y, x)| image[*y][*x] == '#')
+                        .map(|(y, x)| point(x as isize, y as isize))
+                        .collect();
+
+        Ok(Self { galaxies, empty_rows, empty_cols })
+    }
+}
+
+impl Universe {
+
+    // index pairs (i, j) with i < j
+    pub fn pairs(&self) -> impl Iterator<Item = (usize, usize)> {
+        let n = self.galaxies.len();
+        iproduct!(0..n, 0..n).filter(|(i, j)| i < j)
+    }
+
+    // distance between galaxies i and j (0 based) when every empty row and column
+    // is replaced by `factor` rows or columns, None on overflow, a negative factor
+    // or an unknown galaxy
+    pub fn distance(&self, i: usize, j: usize, factor: isize) -> Option<isize> {
+        if factor < 0 {
+            return None;
+        }
+        let (a, b) = (*self.galaxies.get(i)?, *self.galaxies.get(j)?);
+        manhatten_checked(a, b, &self.empty_rows, &self.empty_cols, factor.checked_sub(1)?)
+    }
+
+    pub fn sum_distances(&self, factor: isize) -> Option<isize> {
+        self.pairs().try_fold(0isize, |acc, (i, j)| acc.checked_add(self.distance(i, j, factor)?))
+    }
+
+    pub fn closest_pair(&self, factor: isize) -> Option<(usize, usize, isize)> {
+        self.pair_distances(factor)?.into_iter().min_by_key(|(_, _, d)| *d)
+    }
+
+    pub fn farthest_pair(&self, factor: isize) -> Option<(usize, usize, isize)> {
+        self.pair_distances(factor)?.into_iter().max_by_key(|(_, _, d)| *d)
+    }
+
+    fn pair_distances(&self, factor: isize) -> Option<Vec<(usize, usize, isize)>> {
+        self.pairs().map(|(i, j)| Some((i, j, self.distance(i, j, factor)?))).collect()
+    }
+}
 
 pub fn day11(args: &[String]) {
     println!("Day 11");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args.len() == 3 || args.len() > 4 {
+        println!("Missing input file, optional expansion factor and galaxy numbers");
         return;
     }
     let filename = &args[0];
@@ -14,37 +92,52 @@ pub fn day11(args: &[String]) {
     let contents = fs::read_to_string(filename)
         .expect("Something went wrong reading the file");
 
-    let image: Vec<Vec<_>> = contents.lines()
-        .map(|l| l.chars().collect())
-        .collect();
-    
-    let empty_rows: Vec<_> = (0..image.len()).into_iter()
-                                .filter(|r| (0..image[*r].len()).into_iter().all(|c| image[*r][c] == '.'))
-                                .map(|i| i as isize).collect();
-    let empty_cols: Vec<_> = (0..image[0].len()).into_iter()
-                                .filter(|c| (0..image.len()).into_iter().all(|r| image[r][*c] == '.'))
-                                .map(|i| i as isize).collect();
-    // println!("{:?} {:?}", empty_rows, empty_cols);
-
-    let galaxies: Vec<_> = iproduct!(0..image.len(), 0..image[0].len())
-                    .filter(|(y, x)| image[*y][*x] == '#')
-                    .map(|(y, x)| point(x as isize, y as isize))
-                    .collect();
-    // println!("{:?}", galaxies);
-
-    let galaxy_pairs: Vec<_> = iproduct!(0..galaxies.len(), 0..galaxies.len()).filter(|(i, j)| i < j)
-        .map(|(i, j)| ((galaxies[i], galaxies[j])))
-        .collect();
-
-    let part1: isize = galaxy_pairs.iter()
-                            .map(|(a, b)| manhatten2(*a, *b, &empty_rows, &empty_cols, 1))
-                            .sum();
-    println!("Part 1: {}", part1);
-
-    let part2: isize = galaxy_pairs.iter()
-                            .map(|(a, b)| manhatten2(*a, *b, &empty_rows, &empty_cols, 1000000-1))
-                            .sum();
-    println!("Part 2: {}", part2);
+    let universe: Universe = match contents.parse() {
+        Ok(universe) => universe,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    println!("Part 1: {}", universe.sum_distances(2).unwrap());
+    println!("Part 2: {}", universe.sum_distances(1_000_000).unwrap());
+
+    if args.len() < 2 {
+        return;
+    }
+    let factor: isize = match args[1].parse() {
+        Ok(factor) if factor >= 0 => factor,
+        _ => {
+            println!("Expansion factor must be a non-negative integer");
+            return;
+        }
+    };
+    match universe.sum_distances(factor) {
+        Some(total) => println!("Expansion {}: {}", factor, total),
+        None => {
+            println!("Expansion {}: overflow", factor);
+            return;
+        }
+    }
+    // galaxies are numbered from 1 as in the puzzle text
+    if let Some((i, j, d)) = universe.closest_pair(factor) {
+        println!("Closest: galaxy {} and galaxy {} = {}", i + 1, j + 1, d);
+    }
+    if let Some((i, j, d)) = universe.farthest_pair(factor) {
+        println!("Farthest: galaxy {} and galaxy {} = {}", i + 1, j + 1, d);
+    }
+    if args.len() == 4 {
+        let n = universe.galaxies.len();
+        let (a, b) = match (args[2].parse::<usize>(), args[3].parse::<usize>()) {
+            (Ok(a), Ok(b)) if (1..=n).contains(&a) && (1..=n).contains(&b) => (a, b),
+            _ => {
+                println!("Galaxy numbers must be between 1 and {}", n);
+                return;
+            }
+        };
+        println!("Galaxy {} to galaxy {}: {}", a, b, universe.distance(a - 1, b - 1, factor).unwrap());
+    }
 }
 
 pub fn manhatten(a: Point<isize>, b: Point<isize>) -> isize {
@@ -52,13 +145,15 @@ pub fn manhatten(a: Point<isize>, b: Point<isize>) -> isize {
     d.x.abs() + d.y.abs()
 }
 
-pub fn manhatten2(a: Point<isize>, b: Point<isize>, empty_rows: &Vec<isize>, empty_cols: &Vec<isize>, multiplier: isize) -> isize {
+// manhatten distance with `multiplier` extra steps per empty row or column crossed,
+// None on overflow
+pub fn manhatten_checked(a: Point<isize>, b: Point<isize>, empty_rows: &[isize], empty_cols: &[isize], multiplier: isize) -> Option<isize> {
     let xrange = if a.x < b.x { a.x..b.x } else { b.x..a.x };
     let yrange = if a.y < b.y { a.y..b.y } else { b.y..a.y };
-    
-    let xexp = multiplier * empty_cols.iter().filter(|c| xrange.contains(c)).count() as isize;
-    let yexp = multiplier * empty_rows.iter().filter(|r| yrange.contains(r)).count() as isize;
 
-    let d = b - a;
-    d.x.abs() + d.y.abs() + xexp + yexp
+    let xexp = multiplier.checked_mul(empty_cols.iter().filter(|c| xrange.contains(c)).count() as isize)?;
+    let yexp = multiplier.checked_mul(empty_rows.iter().filter(|r| yrange.contains(r)).count() as isize)?;
+
+    let d = b.x.checked_sub(a.x)?.checked_abs()?.checked_add(b.y.checked_sub(a.y)?.checked_abs()?)?;
+    d.checked_add(xexp)?.checked_add(yexp)
 }
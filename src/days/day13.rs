@@ -1,112 +1,258 @@
 use std::fs;
+use std::fmt;
+use std::str::FromStr;
 use itertools::iproduct;
-use std::iter::zip;
 
-type Map = Vec<Vec<char>>;
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Axis {
+    Horizontal,
+    Vertical,
+}
 
-trait Score {
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Reflection {
+    pub axis: Axis,
+    // number of rows above (horizontal) or columns left (vertical) of the line
+    pub line: usize,
+    // number of cells that differ from their mirror image
+    pub differences: usize,
+}
 
-    fn transform(&self, map: &Map) -> Map {
-        let mut map2 = vec![vec!['_'; map.len()]; map[0].len()];
-        iproduct!(0..map.len(), 0..map[0].len())
-            .for_each(|(y, x)| map2[x][y] = map[y][x]);
-        map2
+impl Reflection {
+    pub fn score(&self) -> usize {
+        match self.axis {
+            Axis::Horizontal => self.line * 100,
+            Axis::Vertical => self.line,
+        }
     }
+}
 
-    fn valid_fold_row(&self, rmap: &Map, row: usize) -> bool;
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Smudge {
+    // (y, x) of the cell that was flipped
+    pub position: (usize, usize),
+    pub repaired: Pattern,
+    pub reflection: Reflection,
+}
 
-    fn mirror_row(&self, map: &Map) -> Option<usize> {
-        (0..map.len()).filter(|i| self.valid_fold_row(map, *i)).next()
-    }
+// each row (and column) is stored as a bitmask with '#' as 1,
+// bit x of rows[y] and bit y of cols[x] are the same cell
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Pattern {
+    rows: Vec<u64>,
+    cols: Vec<u64>,
+}
 
-    fn score(&self, map: &Map) -> usize {
-        match self.mirror_row(map) {
-            Some(v) => (v + 1) * 100,
-            None => {
-                match self.mirror_row(&self.transform(map)) {
-                    Some(v) => v + 1,
-                    None => unreachable!()
-                }
-            }
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum PatternError {
+    Empty,
+    TooLarge { height: usize, width: usize },
+    // 1 based row whose length differs from the first row
+    RaggedRow(usize),
+    InvalidCell(char),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternError::Empty => write!(f, "empty pattern"),
+            PatternError::TooLarge { height, width } =>
+                write!(f, "pattern is {}x{}, patterns are limited to 64x64", height, width),
+            PatternError::RaggedRow(row) => write!(f, "row {} has a different length from row 1", row),
+            PatternError::InvalidCell(c) => write!(f, "invalid cell {:?}", c),
         }
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct Part1 {
-    map: Map
-}
+impl std::error::Error for PatternError {}
 
-impl Score for Part1 {
+impl FromStr for Pattern {
+    type Err = PatternError;
 
-    fn valid_fold_row(&self, map: &Map, row: usize) -> bool {
-        let mut dist: usize = 0;
-        loop {
-            if (row as isize - dist as isize) < 0 || row + dist + 1 >= map.len() {
-                break;
-            }
-            if map[row - dist] != map[row + dist + 1] {
-                return false;
-            }
-            dist += 1; 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let map: Vec<Vec<char>> = s.lines().map(|l| l.chars().collect()).collect();
+        let (height, width) = (map.len(), map.first().map_or(0, |r| r.len()));
+        if width == 0 {
+            return Err(PatternError::Empty);
+        }
+        if height > 64 || width > 64 {
+            return Err(PatternError::TooLarge { height, width });
+        }
+        if let Some(y) = map.iter().position(|r| r.len() != width) {
+            return Err(PatternError::RaggedRow(y + 1));
         }
-        dist > 0
+        if let Some(c) = map.iter().flatten().find(|c| **c != '#' && **c != '.') {
+            return Err(PatternError::InvalidCell(*c));
+        }
+        let mut rows = vec![0; height];
+        let mut cols = vec![0; width];
+        iproduct!(0..height, 0..width)
+            .filter(|(y, x)| map[*y][*x] == '#')
+            .for_each(|(y, x)| {
+                rows[y] |= 1 << x;
+                cols[x] |= 1 << y;
+            });
+        Ok(Self { rows, cols })
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct Part2 {
-    map: Map
-}
+impl Pattern {
+
+    pub fn get(&self, y: usize, x: usize) -> char {
+        if self.rows[y] & (1 << x) != 0 { '#' } else { '.' }
+    }
+
+    // all reflection lines with at most `smudges` differing cells
+    pub fn reflections(&self, smudges: usize) -> Vec<Reflection> {
+        let mut found = fold_lines(&self.rows, Axis::Horizontal, smudges);
+        found.append(&mut fold_lines(&self.cols, Axis::Vertical, smudges));
+        found
+    }
 
-impl Score for Part2 {
+    // sum of the scores of the reflection lines with exactly `smudges` differing cells
+    pub fn score(&self, smudges: usize) -> Option<usize> {
+        let exact: Vec<_> = self.reflections(smudges).into_iter()
+                                .filter(|r| r.differences == smudges).collect();
+        if exact.is_empty() {
+            return None;
+        }
+        Some(exact.iter().map(|r| r.score()).sum())
+    }
 
-    fn valid_fold_row(&self, map: &Map, row: usize) -> bool {
-        let mut dist: usize = 0;
-        let mut diffcount = 0;
-        loop {
-            if (row as isize - dist as isize) < 0 || row + dist + 1 >= map.len() {
-                break;
+    // the single cell that, once flipped, gives a new perfect reflection line
+    pub fn find_smudge(&self) -> Option<Smudge> {
+        let reflection = *self.reflections(1).iter().find(|r| r.differences == 1)?;
+        let position = match reflection.axis {
+            Axis::Horizontal => fold_mismatch(&self.rows, reflection.line - 1)?,
+            Axis::Vertical => {
+                let (x, y) = fold_mismatch(&self.cols, reflection.line - 1)?;
+                (y, x)
             }
-            diffcount += zip(map[row - dist].iter(), map[row + dist + 1].iter())
-                            .filter(|(a, b)| a != b).count();
-            if diffcount > 1 {
-                return false;
+        };
+        let mut repaired = self.clone();
+        repaired.rows[position.0] ^= 1 << position.1;
+        repaired.cols[position.1] ^= 1 << position.0;
+        Some(Smudge {
+            position,
+            repaired,
+            reflection: Reflection { differences: 0, ..reflection },
+        })
+    }
+
+    // the pattern with the mirror line marked as in the puzzle text and `smudge` highlighted
+    pub fn render(&self, reflection: &Reflection, smudge: Option<(usize, usize)>) -> String {
+        let (height, width) = (self.rows.len(), self.cols.len());
+        let cell = |y: usize, x: usize| {
+            if smudge == Some((y, x)) {
+                format!("\x1b[7m{}\x1b[0m", self.get(y, x))
+            } else {
+                self.get(y, x).to_string()
             }
-            dist += 1; 
+        };
+        let mut output = Vec::new();
+        match reflection.axis {
+            Axis::Horizontal => {
+                let digits = height.to_string().len();
+                for y in 0..height {
+                    let marker = if y + 1 == reflection.line { 'v' } else if y == reflection.line { '^' } else { ' ' };
+                    let row: String = (0..width).map(|x| cell(y, x)).collect();
+                    output.push(format!("{:>digits$}{}{}{}{}", y + 1, marker, row, marker, y + 1));
+                }
+            },
+            Axis::Vertical => {
+                let numbers: String = (1..=width).map(|x| char::from_digit((x % 10) as u32, 10).unwrap()).collect();
+                let markers: String = (0..width)
+                    .map(|x| if x + 1 == reflection.line { '>' } else if x == reflection.line { '<' } else { ' ' })
+                    .collect();
+                output.push(numbers.clone());
+                output.push(markers.clone());
+                for y in 0..height {
+                    output.push((0..width).map(|x| cell(y, x)).collect());
+                }
+                output.push(markers);
+                output.push(numbers);
+            }
+        }
+        output.join("\n")
+    }
+}
+
+// number of differing cells when folding after `line`, None once over `limit`
+fn fold_differences(lines: &[u64], line: usize, limit: usize) -> Option<usize> {
+    let mut diffcount = 0;
+    for dist in 0..=line {
+        if line + dist + 1 >= lines.len() {
+            break;
+        }
+        diffcount += (lines[line - dist] ^ lines[line + dist + 1]).count_ones() as usize;
+        if diffcount > limit {
+            return None;
         }
-        dist > 0 && diffcount == 1
     }
+    Some(diffcount)
 }
 
+// first (line, bit) on the near side of the fold that differs from its mirror image
+fn fold_mismatch(lines: &[u64], line: usize) -> Option<(usize, usize)> {
+    (0..=line).take_while(|dist| line + dist + 1 < lines.len())
+        .map(|dist| (line - dist, lines[line - dist] ^ lines[line + dist + 1]))
+        .find(|(_, diff)| *diff != 0)
+        .map(|(l, diff)| (l, diff.trailing_zeros() as usize))
+}
+
+fn fold_lines(lines: &[u64], axis: Axis, smudges: usize) -> Vec<Reflection> {
+    (0..lines.len().saturating_sub(1))
+        .filter_map(|line| fold_differences(lines, line, smudges)
+            .map(|differences| Reflection { axis, line: line + 1, differences }))
+        .collect()
+}
+
+/*
+ Solution command:
+    cargo run -- day13 data/day13.txt [show]
+*/
+
 pub fn day13(args: &[String]) {
     println!("Day 13");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args.len() > 2 || (args.len() == 2 && args[1] != "show") {
+        println!("Missing input file, optional 'show'");
         return;
     }
     let filename = &args[0];
     println!("In file {}", filename);
     let contents = fs::read_to_string(filename)
         .expect("Something went wrong reading the file");
-    
-    let patterns: Vec<Vec<Vec<char>>> = contents.split("\n\n")
-                    .map(|p| p.lines()
-                        .map(|l| l.chars().collect()).collect()
-                    ).collect();
-
-    // Part 1
-    let part1: usize = patterns.iter().map(|p| {
-            let pattern = Part1 { map: p.to_vec() };
-            pattern.score(&pattern.map)
-        }).sum();
-    println!("Part 1: {}", part1);
-
-    // Part 2
-    let part2: usize = patterns.iter().map(|p| {
-        let pattern = Part2 { map: p.to_vec() };
-        pattern.score(&pattern.map)
-    }).sum();
-    println!("Part 2: {}", part2);
 
+    let patterns: Result<Vec<Pattern>, _> = contents.trim_end().split("\n\n").enumerate()
+                    .map(|(i, p)| p.parse().map_err(|e| (i, e)))
+                    .collect();
+    let patterns = match patterns {
+        Ok(patterns) => patterns,
+        Err((i, e)) => {
+            println!("Error in pattern {}: {}", i + 1, e);
+            return;
+        }
+    };
+
+    for (part, smudges) in [(1, 0), (2, 1)] {
+        let scores: Vec<_> = patterns.iter().map(|p| p.score(smudges)).collect();
+        println!("Part {}: {}", part, scores.iter().flatten().sum::<usize>());
+        for (i, _) in scores.iter().enumerate().filter(|(_, s)| s.is_none()) {
+            println!("  no reflection with {} smudge(s) in pattern {}", smudges, i + 1);
+        }
+    }
+
+    if args.len() == 2 {
+        for (i, pattern) in patterns.iter().enumerate() {
+            println!("\nPattern {}", i + 1);
+            match pattern.find_smudge() {
+                Some(smudge) => {
+                    println!("Smudge at row {} column {}, new {:?} line {}",
+                        smudge.position.0 + 1, smudge.position.1 + 1, smudge.reflection.axis, smudge.reflection.line);
+                    println!("{}", pattern.render(&smudge.reflection, Some(smudge.position)));
+                },
+                None => println!("No smudge found"),
+            }
+        }
+    }
 }
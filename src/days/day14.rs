@@ -1,12 +1,26 @@
 use std::fs;
+use std::fmt;
 use std::str::FromStr;
 use std::num::ParseIntError;
 use itertools::iproduct;
+use regex::Regex;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct Platform {
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    North,
+    West,
+    South,
+    East,
+}
+
+const SPIN: [Direction; 4] = [Direction::North, Direction::West, Direction::South, Direction::East];
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Platform {
     map: Vec<Vec<char>>,
 }
 
@@ -22,49 +36,204 @@ impl FromStr for Platform {
 
 impl Platform {
 
-    fn rotate(&mut self) {
-        let mut map = vec![vec!['_'; self.map.len()]; self.map[0].len()];
-        let h = self.map.len() - 1;
-        iproduct!(0..self.map.len(), 0..self.map[0].len())
-            .for_each(|(y, x)| map[x][h - y] = self.map[y][x]);
-        self.map = map;
+    // (y, x) of the i-th position along `line` when moving towards `direction`'s edge
+    fn position(&self, direction: Direction, line: usize, i: usize) -> (usize, usize) {
+        match direction {
+            Direction::North => (i, line),
+            Direction::South => (self.map.len() - 1 - i, line),
+            Direction::West => (line, i),
+            Direction::East => (line, self.map[0].len() - 1 - i),
+        }
+    }
+
+    // number of lines running towards `direction`'s edge and their length
+    fn lines(&self, direction: Direction) -> (usize, usize) {
+        match direction {
+            Direction::North | Direction::South => (self.map[0].len(), self.map.len()),
+            Direction::West | Direction::East => (self.map.len(), self.map[0].len()),
+        }
     }
 
-    fn tilt(&mut self) {
-        for x in 0..self.map[0].len() {
+    pub fn tilt(&mut self, direction: Direction) {
+        let (lines, len) = self.lines(direction);
+        for line in 0..lines {
             let mut block = 0;
-            for y in 0..self.map.len() {
-                if self.map[y][x] == 'O' && block < y {
-                    self.map[block][x] = 'O';
-                    self.map[y][x] = '.';
-                    block += 1;
-                }
+            for i in 0..len {
+                let (y, x) = self.position(direction, line, i);
                 match self.map[y][x] {
-                    'O' | '#' => block = y + 1,
-                    _ => (), 
+                    'O' => {
+                        if block < i {
+                            let (by, bx) = self.position(direction, line, block);
+                            self.map[by][bx] = 'O';
+                            self.map[y][x] = '.';
+                        }
+                        block += 1;
+                    },
+                    '#' => block = i + 1,
+                    _ => (),
                 }
             }
         }
     }
 
-    fn score(&self) -> usize {
-        let mut total = 0;
-        for (i, y) in (0..self.map.len()).rev().enumerate() {
-            for x in 0..self.map[y].len() {
-                if self.map[y][x] == 'O' {
-                    total += i + 1;
+    pub fn spin_cycle(&mut self) {
+        self.tilt_sequence(&SPIN);
+    }
+
+    pub fn tilt_sequence(&mut self, directions: &[Direction]) {
+        for direction in directions {
+            self.tilt(*direction);
+        }
+    }
+
+    // appends the rounded rock positions as a bitset of cells
+    fn pack_into(&self, out: &mut Vec<u64>) {
+        let (height, width) = (self.map.len(), self.map[0].len());
+        let base = out.len();
+        out.resize(base + (height * width).div_ceil(64), 0);
+        iproduct!(0..height, 0..width)
+            .filter(|(y, x)| self.map[*y][*x] == 'O')
+            .for_each(|(y, x)| {
+                let i = y * width + x;
+                out[base + i / 64] |= 1 << (i % 64);
+            });
+    }
+
+    // applies `directions` up to `limit` times, stopping at the first repeated layout;
+    // returns the platform reached, the repeats applied and the loop start and length if found
+    fn walk_to_loop(&self, directions: &[Direction], limit: usize) -> (Platform, usize, Option<(usize, usize)>) {
+        let mut platform = self.clone();
+        let words = (self.map.len() * self.map[0].len()).div_ceil(64);
+        // packed layouts one after another, indexed by repeat count
+        let mut states: Vec<u64> = Vec::new();
+        let mut seen: HashMap<u64, usize> = HashMap::new();
+        for r in 0..=limit {
+            let start = states.len();
+            platform.pack_into(&mut states);
+            let mut hasher = DefaultHasher::new();
+            states[start..].hash(&mut hasher);
+            // the hash only picks the candidate, the packed layouts are compared exactly
+            if let Some(prev) = seen.insert(hasher.finish(), r) {
+                if states[prev * words..(prev + 1) * words] == states[start..] {
+                    return (platform, r, Some((prev, r - prev)));
                 }
             }
+            if r == limit {
+                break;
+            }
+            platform.tilt_sequence(directions);
+        }
+        (platform, limit, None)
+    }
+
+    // returns the index of the first repeated layout and the loop length
+    // when applying `directions` over and over
+    pub fn find_loop(&self, directions: &[Direction]) -> (usize, usize) {
+        self.walk_to_loop(directions, usize::MAX).2.expect("a finite platform always repeats")
+    }
+
+    // the platform after applying `directions` `repeat` times
+    pub fn after_repeats(&self, directions: &[Direction], repeat: usize) -> Platform {
+        let (mut platform, reached, found) = self.walk_to_loop(directions, repeat);
+        if let Some((_, loop_size)) = found {
+            for _ in 0..(repeat - reached) % loop_size {
+                platform.tilt_sequence(directions);
+            }
+        }
+        platform
+    }
+
+    pub fn find_cycle(&self) -> (usize, usize) {
+        self.find_loop(&SPIN)
+    }
+
+    // the platform after `cycles` spin cycles
+    pub fn after_cycles(&self, cycles: usize) -> Platform {
+        self.after_repeats(&SPIN, cycles)
+    }
+
+    pub fn run(&self, script: &TiltScript) -> Platform {
+        script.steps.iter().fold(self.clone(), |platform, (directions, repeat)| platform.after_repeats(directions, *repeat))
+    }
+
+    // total load on the beams along `edge`
+    pub fn load(&self, edge: Direction) -> usize {
+        let (lines, len) = self.lines(edge);
+        iproduct!(0..lines, 0..len)
+            .filter(|(line, i)| {
+                let (y, x) = self.position(edge, *line, *i);
+                self.map[y][x] == 'O'
+            })
+            .map(|(_, i)| len - i)
+            .sum()
+    }
+
+    pub fn score(&self) -> usize {
+        self.load(Direction::North)
+    }
+
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in &self.map {
+            writeln!(f, "{}", row.iter().collect::<String>())?;
+        }
+        Ok(())
+    }
+}
+
+// tilt directions with optional repeat counts, e.g. "N" or "NWSE*1000" or "NW*2SE"
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TiltScript {
+    steps: Vec<(Vec<Direction>, usize)>,
+}
+
+impl FromStr for TiltScript {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"([NWSE]+)(?:\*(\d+))?").unwrap();
+        }
+        let s = s.trim();
+        let mut steps = vec![];
+        let mut end = 0;
+        for caps in RE.captures_iter(s) {
+            let m = caps.get(0).unwrap();
+            if m.start() != end {
+                return Err(format!("Invalid tilt script at: {}", &s[end..]));
+            }
+            end = m.end();
+            let directions = caps[1].chars().map(|c| match c {
+                'N' => Direction::North,
+                'W' => Direction::West,
+                'S' => Direction::South,
+                'E' => Direction::East,
+                _ => unreachable!()
+            }).collect();
+            let repeat = match caps.get(2) {
+                Some(r) => r.as_str().parse().map_err(|e| format!("Invalid repeat {}: {}", r.as_str(), e))?,
+                None => 1,
+            };
+            steps.push((directions, repeat));
+        }
+        if steps.is_empty() || end != s.len() {
+            return Err(format!("Invalid tilt script at: {}", &s[end..]));
         }
-        total
+        Ok(Self { steps })
     }
-   
 }
 
+/*
+ Solution command:
+    cargo run -- day14 data/day14.txt [tilt script, e.g. NWSE*1000]
+*/
+
 pub fn day14(args: &[String]) {
     println!("Day 14");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args.len() > 2 {
+        println!("Missing input file, optional tilt script");
         return;
     }
     let filename = &args[0];
@@ -74,52 +243,27 @@ pub fn day14(args: &[String]) {
     
     // Part 1
     let mut platform: Platform = contents.parse().unwrap();
-    platform.tilt();
+    platform.tilt(Direction::North);
     println!("Part 1: {}", platform.score());
     
     // Part 2
     platform = contents.parse().unwrap();
-    let mut loop_start = 0;
-    let mut loop_size = 0;
-    let mut count_pos = HashMap::new();
-    let mut scores = vec![];
-    let mut count_nochange = 0;
-    for r in 0.. {
-        for _ in 0..4 {
-            platform.tilt();
-            platform.rotate();
-        }
-        let s = platform.score();
-        // detect the repeating pattern
-        scores.push(s);
-        if count_pos.contains_key(&s) {
-            let d = r - count_pos.remove(&s).unwrap();
-            if d > loop_size {
-                loop_size = d;
-                count_nochange = 0;
-            } else {
-                count_nochange += 1;
-            }
-            // println!("{} | {} {} = {}", loop_size, r, s, d);
-        }
-        if count_nochange > 100 {
-            let mut loop_detected = true;
-            for i in 0..loop_size {
-                if scores[r - i] != scores[r - i - loop_size] {
-                    loop_detected = false;
-                    break;
-                }
-            }
-            if loop_detected {
-                loop_start = r - loop_size;
-                println!("Loop start: {} size : {}", loop_start, loop_size);
-                break;
+    let (loop_start, loop_size) = platform.find_cycle();
+    println!("Loop start: {} size : {}", loop_start, loop_size);
+    println!("Part 2: {}", platform.after_cycles(1_000_000_000).score());
+
+    if args.len() == 2 {
+        let script: TiltScript = match args[1].parse() {
+            Ok(script) => script,
+            Err(e) => {
+                println!("{}", e);
+                return;
             }
+        };
+        let result = platform.run(&script);
+        println!("\n{}", result);
+        for edge in SPIN {
+            println!("Load {:?}: {}", edge, result.load(edge));
         }
-        count_pos.insert(s, r);
     }
-    let offset = (1_000_000_000 - 1 - loop_start) % loop_size;
-    let pattern = &scores[loop_start..loop_start+loop_size];
-
-    println!("Part 2: {}", pattern[offset]); 
 }
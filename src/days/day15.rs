@@ -1,40 +1,200 @@
 use std::fs;
+use std::fmt;
 use std::str::FromStr;
-use std::num::ParseIntError;
-use regex::Regex;
-use ascii_converter::string_to_decimals;
 
 
 #[derive(Clone, Eq, PartialEq, Debug)]
-struct Step {
-    name: String,
-    length: Option<usize>,
+pub struct Step {
+    pub name: String,
+    pub length: Option<usize>,
 }
 
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum StepError {
+    Empty,
+    InvalidLabel(String),
+    MissingOperation(String),
+    InvalidFocalLength(String),
+    UnexpectedFocalLength(String),
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepError::Empty => write!(f, "empty step"),
+            StepError::InvalidLabel(label) => write!(f, "invalid label {:?}", label),
+            StepError::MissingOperation(step) => write!(f, "missing '=' or '-' in {:?}", step),
+            StepError::InvalidFocalLength(length) => write!(f, "focal length {:?} is not between 1 and 9", length),
+            StepError::UnexpectedFocalLength(length) => write!(f, "unexpected {:?} after '-'", length),
+        }
+    }
+}
+
+impl std::error::Error for StepError {}
+
 impl FromStr for Step {
-    type Err = ParseIntError;
+    type Err = StepError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"(\w+)([=\-])(\d*)").unwrap();
+        if s.is_empty() {
+            return Err(StepError::Empty);
+        }
+        let op = s.find(['=', '-']).ok_or_else(|| StepError::MissingOperation(s.to_string()))?;
+        let (name, rest) = (&s[..op], &s[op + 1..]);
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(StepError::InvalidLabel(name.to_string()));
         }
-        let caps = RE.captures(s).unwrap();
-        Ok(Self {
-            name: caps[1].to_string(),
-            length: if caps[2].to_string() == "=" { Some(caps[3].parse().unwrap()) } else { None },
-        })
+        let length = match &s[op..op + 1] {
+            "=" => match rest.parse() {
+                Ok(length @ 1..=9) if rest.len() == 1 => Some(length),
+                _ => return Err(StepError::InvalidFocalLength(rest.to_string())),
+            },
+            _ if rest.is_empty() => None,
+            _ => return Err(StepError::UnexpectedFocalLength(rest.to_string())),
+        };
+        Ok(Self { name: name.to_string(), length })
+    }
+}
+
+// a step that failed to parse and its position (0 based) in the sequence
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ParseStepsError {
+    pub index: usize,
+    pub step: String,
+    pub error: StepError,
+}
+
+impl fmt::Display for ParseStepsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "step {} ({:?}): {}", self.index, self.step, self.error)
     }
 }
 
-fn hash(step: &str) -> usize {
-    string_to_decimals(step).unwrap().iter()
-        .fold(0, |acc, x| ((acc + *x as usize) * 17) % 256)
+impl std::error::Error for ParseStepsError {}
+
+// the comma separated steps with newlines removed, as the puzzle ignores them
+pub fn split_steps(contents: &str) -> Vec<String> {
+    let joined: String = contents.chars().filter(|c| *c != '\n' && *c != '\r').collect();
+    joined.split(',').map(|s| s.trim().to_string()).collect()
 }
 
+pub fn parse_steps(contents: &str) -> Result<Vec<Step>, ParseStepsError> {
+    split_steps(contents).into_iter().enumerate()
+        .map(|(index, step)| step.parse().map_err(|error| ParseStepsError { index, step, error }))
+        .collect()
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.length {
+            Some(length) => write!(f, "{}={}", self.name, length),
+            None => write!(f, "{}-", self.name),
+        }
+    }
+}
+
+pub fn hash(step: &str) -> usize {
+    step.bytes().fold(0, |acc, b| ((acc + b as usize) * 17) % 256)
+}
+
+// the HASHMAP: 256 boxes of labelled lenses kept in insertion order
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct LensHashMap {
+    boxes: Vec<Vec<(String, usize)>>,
+}
+
+impl Default for LensHashMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LensHashMap {
+
+    pub fn new() -> Self {
+        Self { boxes: vec![vec![]; 256] }
+    }
+
+    // replaces the focal length of an existing lens in place, returning the old one,
+    // or adds the lens to the back of its box
+    pub fn insert(&mut self, label: &str, length: usize) -> Option<usize> {
+        let lenses = &mut self.boxes[hash(label)];
+        match lenses.iter_mut().find(|(l, _)| l == label) {
+            Some((_, old)) => Some(std::mem::replace(old, length)),
+            None => {
+                lenses.push((label.to_string(), length));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, label: &str) -> Option<usize> {
+        let lenses = &mut self.boxes[hash(label)];
+        let pos = lenses.iter().position(|(l, _)| l == label)?;
+        Some(lenses.remove(pos).1)
+    }
+
+    pub fn get(&self, label: &str) -> Option<usize> {
+        self.boxes[hash(label)].iter().find(|(l, _)| l == label).map(|(_, length)| *length)
+    }
+
+    pub fn apply(&mut self, step: &Step) {
+        match step.length {
+            Some(length) => { self.insert(&step.name, length); },
+            None => { self.remove(&step.name); }
+        }
+    }
+
+    // (box number, label, focal length) in box then slot order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str, usize)> {
+        self.boxes.iter().enumerate()
+            .flat_map(|(bnum, b)| b.iter().map(move |(label, length)| (bnum, label.as_str(), *length)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.boxes.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boxes.iter().all(|b| b.is_empty())
+    }
+
+    // the state of the boxes after each step
+    pub fn history(&self, steps: &[Step]) -> Vec<LensHashMap> {
+        let mut boxes = self.clone();
+        steps.iter().map(|step| {
+            boxes.apply(step);
+            boxes.clone()
+        }).collect()
+    }
+
+    pub fn focusing_power(&self) -> usize {
+        self.boxes.iter().enumerate().map(|(bnum, b)|
+            b.iter().enumerate().map(|(snum, (_, length))| (bnum + 1) * (snum + 1) * length).sum::<usize>()
+        ).sum()
+    }
+}
+
+// non-empty boxes in the puzzle's "Box 0: [rn 1] [cm 2]" format
+impl fmt::Display for LensHashMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (bnum, b) in self.boxes.iter().enumerate().filter(|(_, b)| !b.is_empty()) {
+            let lenses: Vec<_> = b.iter().map(|(label, length)| format!("[{} {}]", label, length)).collect();
+            writeln!(f, "Box {}: {}", bnum, lenses.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ Solution command:
+    cargo run -- day15 data/day15.txt [trace]
+*/
+
 pub fn day15(args: &[String]) {
     println!("Day 15");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args.len() > 2 || (args.len() == 2 && args[1] != "trace") {
+        println!("Missing input file, optional 'trace'");
         return;
     }
     let filename = &args[0];
@@ -43,28 +203,23 @@ pub fn day15(args: &[String]) {
         .expect("Something went wrong reading the file");
 
     // Part 1
-    let part1: usize = contents.split(",").map(|s| hash(s)).sum();
+    let part1: usize = split_steps(&contents).iter().map(|s| hash(s)).sum();
     println!("Part 1: {:?}", part1);
 
     // Part 2
-    let steps: Vec<Step> = contents.split(",").map(|s| s.parse().unwrap()).collect();
-    let mut boxes: Vec<Vec<Step>> = vec![vec![]; 256];
-    for step in steps {
-        let h = hash(&step.name);
-        match boxes[h].iter().position(|b| b.name == step.name) {
-            Some(pos) => match step.length {
-                Some(_) => boxes[h][pos] = step,
-                None => { boxes[h].remove(pos); }
-            },
-            None => match step.length {
-                Some(_) => boxes[h].push(step),
-                None => ()
-            }
+    let steps = match parse_steps(&contents) {
+        Ok(steps) => steps,
+        Err(e) => {
+            println!("Invalid {}", e);
+            return;
+        }
+    };
+    if args.len() == 2 {
+        for (step, boxes) in steps.iter().zip(LensHashMap::new().history(&steps)) {
+            println!("After \"{}\":\n{}", step, boxes);
         }
     }
-
-    let part2: usize = boxes.iter().enumerate().map(|(bnum, b)|
-            b.iter().enumerate().map(|(snum, s)| (bnum + 1) * (snum + 1) * s.length.unwrap()).sum::<usize>()
-        ).sum();
-    println!("Part 2: {:?}", part2);
+    let mut boxes = LensHashMap::new();
+    steps.iter().for_each(|step| boxes.apply(step));
+    println!("Part 2: {:?}", boxes.focusing_power());
 }
@@ -1,6 +1,9 @@
 use std::fs;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
 use itertools::iproduct;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -11,100 +14,513 @@ pub enum Moving {
     Down,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-struct Beam {
-    position: (isize, isize),
-    moving: Moving
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Beam {
+    pub position: (isize, isize),
+    pub moving: Moving
 }
 
 impl Beam {
-    fn new(position: (isize, isize), moving: Moving) -> Self {
+    pub fn new(position: (isize, isize), moving: Moving) -> Self {
         Self { position, moving }
     }
 
-    fn step(self, grid: char) -> Vec<Self> {
+    // the beam one tile further on after leaving its tile moving `moving`
+    fn advance(self, moving: Moving) -> Self {
         let (y, x) = self.position;
-        match grid {
-            '.' => match self.moving {
-                Moving::Right => vec![Beam::new((y, x+1), self.moving)],
-                Moving::Left => vec![Beam::new((y, x-1), self.moving)],
-                Moving::Up => vec![Beam::new((y-1, x), self.moving)],
-                Moving::Down => vec![Beam::new((y+1, x), self.moving)],
-            },
-            '/' => match self.moving {
-                Moving::Right => vec![Beam::new((y-1, x), Moving::Up)],
-                Moving::Left => vec![Beam::new((y+1, x), Moving::Down)],
-                Moving::Up => vec![Beam::new((y, x+1), Moving::Right)],
-                Moving::Down => vec![Beam::new((y, x-1), Moving::Left)],
-            },
-            '\\' => match self.moving {
-                Moving::Right => vec![Beam::new((y+1, x), Moving::Down)],
-                Moving::Left => vec![Beam::new((y-1, x), Moving::Up)],
-                Moving::Up => vec![Beam::new((y, x-1), Moving::Left)],
-                Moving::Down => vec![Beam::new((y, x+1), Moving::Right)],
-            },
-            '-' => match self.moving {
-                Moving::Right => vec![Beam::new((y, x+1), Moving::Right)],
-                Moving::Left => vec![Beam::new((y, x-1), Moving::Left)],
-                Moving::Up | Moving::Down => vec![Beam::new((y, x-1), Moving::Left), Beam::new((y, x+1), Moving::Right)],
-            },
-            '|' => match self.moving {
-                Moving::Right | Moving::Left => vec![Beam::new((y-1, x), Moving::Up), Beam::new((y+1, x), Moving::Down)],
-                Moving::Up => vec![Beam::new((y-1, x), Moving::Up)],
-                Moving::Down => vec![Beam::new((y+1, x), Moving::Down)],
-            }
-            _ => unreachable!()
+        match moving {
+            Moving::Right => Beam::new((y, x+1), moving),
+            Moving::Left => Beam::new((y, x-1), moving),
+            Moving::Up => Beam::new((y-1, x), moving),
+            Moving::Down => Beam::new((y+1, x), moving),
+        }
+    }
+}
+
+// a tile of the contraption, giving the directions of the beams leaving it
+// for a beam entering it moving `moving`
+pub trait Element {
+    fn outputs(&self, moving: Moving) -> Vec<Moving>;
+}
+
+impl<F: Fn(Moving) -> Vec<Moving>> Element for F {
+    fn outputs(&self, moving: Moving) -> Vec<Moving> {
+        self(moving)
+    }
+}
+
+// the elements each tile character stands for
+#[derive(Clone)]
+pub struct Elements {
+    table: HashMap<char, Rc<dyn Element>>,
+}
+
+impl Elements {
+
+    // empty space '.', mirrors '/' and '\' and splitters '-' and '|'
+    pub fn standard() -> Self {
+        let mut elements = Self { table: HashMap::new() };
+        elements.register('.', |m| vec![m]);
+        elements.register('/', |m| vec![match m {
+            Moving::Right => Moving::Up,
+            Moving::Left => Moving::Down,
+            Moving::Up => Moving::Right,
+            Moving::Down => Moving::Left,
+        }]);
+        elements.register('\\', |m| vec![match m {
+            Moving::Right => Moving::Down,
+            Moving::Left => Moving::Up,
+            Moving::Up => Moving::Left,
+            Moving::Down => Moving::Right,
+        }]);
+        elements.register('-', |m| match m {
+            Moving::Right | Moving::Left => vec![m],
+            Moving::Up | Moving::Down => vec![Moving::Left, Moving::Right],
+        });
+        elements.register('|', |m| match m {
+            Moving::Right | Moving::Left => vec![Moving::Up, Moving::Down],
+            Moving::Up | Moving::Down => vec![m],
+        });
+        elements
+    }
+
+    // the standard elements plus absorbers '#', one-way valves '>', '<', '^' and 'v'
+    // and three-way splitters '+' that pass the beam on and split it both ways
+    pub fn extended() -> Self {
+        let mut elements = Self::standard();
+        elements.register('#', |_| vec![]);
+        for (tile, open) in [('>', Moving::Right), ('<', Moving::Left), ('^', Moving::Up), ('v', Moving::Down)] {
+            elements.register(tile, move |m| if m == open { vec![m] } else { vec![] });
         }
+        elements.register('+', |m| match m {
+            Moving::Right | Moving::Left => vec![Moving::Up, m, Moving::Down],
+            Moving::Up | Moving::Down => vec![Moving::Left, m, Moving::Right],
+        });
+        elements
+    }
+
+    pub fn register(&mut self, tile: char, element: impl Element + 'static) {
+        self.table.insert(tile, Rc::new(element));
+    }
+
+    pub fn parse(&self, s: &str) -> Result<Contraption, ContraptionError> {
+        let tiles: Vec<Vec<char>> = s.lines().map(|l| l.chars().collect()).collect();
+        if tiles.is_empty() || tiles[0].is_empty() {
+            return Err(ContraptionError::Empty);
+        }
+        if let Some(row) = tiles.iter().position(|r| r.len() != tiles[0].len()) {
+            return Err(ContraptionError::UnevenRow(row + 1));
+        }
+        let elements = tiles.iter().enumerate().map(|(y, row)|
+            row.iter().enumerate().map(|(x, tile)| match self.table.get(tile) {
+                Some(element) => Ok(element.clone()),
+                None => Err(ContraptionError::UnknownTile { tile: *tile, row: y + 1, column: x + 1 }),
+            }).collect()
+        ).collect::<Result<_, _>>()?;
+        Ok(Contraption { tiles, elements })
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum ContraptionError {
+    Empty,
+    UnevenRow(usize),
+    UnknownTile { tile: char, row: usize, column: usize },
+}
+
+impl fmt::Display for ContraptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContraptionError::Empty => write!(f, "empty contraption"),
+            ContraptionError::UnevenRow(row) => write!(f, "row {} has a different length", row),
+            ContraptionError::UnknownTile { tile, row, column } =>
+                write!(f, "unknown tile {:?} at row {} column {}", tile, row, column),
+        }
+    }
+}
+
+impl std::error::Error for ContraptionError {}
+
+#[derive(Clone)]
+pub struct Contraption {
+    pub tiles: Vec<Vec<char>>,
+    elements: Vec<Vec<Rc<dyn Element>>>,
+}
+
+impl FromStr for Contraption {
+    type Err = ContraptionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Elements::standard().parse(s)
     }
 }
 
+impl Contraption {
+
+    pub fn height(&self) -> usize {
+        self.tiles.len()
+    }
 
-fn move_beam(start: Beam, contraption: &Vec<Vec<char>>) -> usize {
+    pub fn width(&self) -> usize {
+        self.tiles[0].len()
+    }
+
+    pub fn contains(&self, (y, x): (isize, isize)) -> bool {
+        y >= 0 && y < self.height() as isize && x >= 0 && x < self.width() as isize
+    }
+
+    // the beams leaving the tile `beam` is on
+    pub fn step(&self, beam: Beam) -> Vec<Beam> {
+        let (y, x) = beam.position;
+        self.elements[y as usize][x as usize].outputs(beam.moving).into_iter()
+            .map(|m| beam.advance(m)).collect()
+    }
+}
+
+// directions of the beams that passed through each tile
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Energized {
+    pub directions: Vec<Vec<HashSet<Moving>>>,
+}
+
+impl Energized {
+
+    pub fn grid(&self) -> Vec<Vec<bool>> {
+        self.directions.iter().map(|row| row.iter().map(|d| !d.is_empty()).collect()).collect()
+    }
+
+    pub fn count(&self) -> usize {
+        iproduct!(0..self.directions.len(), 0..self.directions[0].len())
+            .filter(|(y, x)| !self.directions[*y][*x].is_empty()).count()
+    }
+
+    // the contraption with energized tiles highlighted
+    pub fn render(&self, contraption: &Contraption) -> String {
+        contraption.tiles.iter().enumerate().map(|(y, row)|
+            row.iter().enumerate().map(|(x, c)|
+                if self.directions[y][x].is_empty() { c.to_string() } else { format!("\x1b[7m{}\x1b[0m", c) }
+            ).collect::<String>()
+        ).collect::<Vec<_>>().join("\n")
+    }
+}
+
+pub fn move_beam(start: Beam, contraption: &Contraption) -> Energized {
     let mut beams = vec![start];
-    let mut energized: Vec<Vec<HashSet<Moving>>> = vec![vec![HashSet::new(); contraption[0].len()]; contraption.len()];
+    let mut energized: Vec<Vec<HashSet<Moving>>> = vec![vec![HashSet::new(); contraption.width()]; contraption.height()];
 
     while let Some(beam) = beams.pop() {
         // println!("{:?} = {:?}", beam, beams.len());
         // filter out beams that move off the contraption
-        if beam.position.0 < 0 || beam.position.0 >= contraption.len() as isize || 
-                beam.position.1 < 0 || beam.position.1 >= contraption[0].len() as isize {
+        if !contraption.contains(beam.position) {
             continue;
         }
         // maintain set of seen beams
         if energized[beam.position.0 as usize][beam.position.1 as usize].insert(beam.moving) {
             // only step new beams 
-            beams.append(&mut beam.step(contraption[beam.position.0 as usize][beam.position.1 as usize]));
+            beams.append(&mut contraption.step(beam));
+        }
+    }
+    Energized { directions: energized }
+}
+
+// beams entering from every tile on the left, right, top and bottom edges
+pub fn edge_entries(contraption: &Contraption) -> Vec<Beam> {
+    let (height, width) = (contraption.height(), contraption.width());
+    let mut beams: Vec<Beam> = vec![];
+    beams.append(&mut (0..height).map(|y| Beam::new((y as isize, 0), Moving::Right)).collect());
+    beams.append(&mut (0..height).map(|y| Beam::new((y as isize, width as isize - 1), Moving::Left)).collect());
+    beams.append(&mut (0..width).map(|x| Beam::new((0, x as isize), Moving::Down)).collect());
+    beams.append(&mut (0..width).map(|x| Beam::new((height as isize - 1, x as isize), Moving::Up)).collect());
+    beams
+}
+
+// tile set stored one bit per tile, row by row
+type Tiles = Vec<u64>;
+
+fn tiles_new(width: usize, height: usize) -> Tiles {
+    vec![0; (width * height).div_ceil(64)]
+}
+
+fn tiles_insert(tiles: &mut Tiles, i: usize) {
+    tiles[i / 64] |= 1 << (i % 64);
+}
+
+fn tiles_union(tiles: &mut Tiles, other: &Tiles) {
+    tiles.iter_mut().zip(other).for_each(|(a, b)| *a |= b);
+}
+
+fn tiles_count(tiles: &Tiles) -> usize {
+    tiles.iter().map(|t| t.count_ones() as usize).sum()
+}
+
+// indices of the tiles in `tiles` but not in `other`
+fn tiles_difference(tiles: &Tiles, other: Option<&Tiles>) -> Vec<usize> {
+    tiles.iter().enumerate().flat_map(|(w, t)| {
+        let mut bits = t & !other.map_or(0, |o| o[w]);
+        std::iter::from_fn(move || {
+            if bits == 0 {
+                return None;
+            }
+            let i = w * 64 + bits.trailing_zeros() as usize;
+            bits &= bits - 1;
+            Some(i)
+        })
+    }).collect()
+}
+
+// Beams only branch (or stop) where a tile turns one beam into any other number of
+// beams, e.g. a splitter hit side on. Those beam states are the nodes of the graph,
+// each owning the tiles on the straight-through segments up to the next nodes.
+// Nodes in the same strongly connected component energize the same tiles, so the
+// components are collapsed and their energized sets built once, sinks first.
+// Most components energize little more than one of their successors, so each
+// set is stored as the tiles it adds to the largest successor's set.
+pub struct BeamGraph {
+    width: usize,
+    height: usize,
+    nodes: HashMap<Beam, usize>,
+    component_of: Vec<usize>,
+    // the successor component whose energized set each component extends
+    base: Vec<Option<usize>>,
+    // tile indices each component energizes beyond its base
+    extra: Vec<Vec<usize>>,
+}
+
+impl BeamGraph {
+
+    pub fn new(contraption: &Contraption) -> Self {
+        let (height, width) = (contraption.height(), contraption.width());
+        let mut graph = Self { width, height, nodes: HashMap::new(), component_of: vec![], base: vec![], extra: vec![] };
+        for (y, x) in iproduct!(0..height, 0..width) {
+            for moving in [Moving::Right, Moving::Left, Moving::Up, Moving::Down] {
+                let beam = Beam::new((y as isize, x as isize), moving);
+                if contraption.step(beam).len() != 1 {
+                    let id = graph.nodes.len();
+                    graph.nodes.insert(beam, id);
+                }
+            }
+        }
+
+        let mut beams: Vec<_> = graph.nodes.iter().map(|(b, id)| (*id, *b)).collect();
+        beams.sort_by_key(|(id, _)| *id);
+        // the tile indices on each node's own segments, only needed until `reach` is built
+        let (segments, edges): (Vec<Vec<usize>>, Vec<Vec<usize>>) = beams.iter().map(|(_, beam)| {
+            let mut tiles = vec![graph.index(beam.position)];
+            let mut next = vec![];
+            for out in contraption.step(*beam) {
+                let (mut t, mut n) = graph.trace(out, contraption);
+                tiles.append(&mut t);
+                next.append(&mut n);
+            }
+            (tiles, next)
+        }).unzip();
+
+        let components = strongly_connected(&edges);
+        let mut component_of = vec![0; edges.len()];
+        components.iter().enumerate().for_each(|(c, members)| members.iter().for_each(|m| component_of[*m] = c));
+        let successors: Vec<Vec<usize>> = components.iter().enumerate().map(|(c, members)| {
+            let mut next: Vec<usize> = members.iter()
+                .flat_map(|m| edges[*m].iter().map(|n| component_of[*n]))
+                .filter(|n| *n != c)
+                .collect();
+            next.sort();
+            next.dedup();
+            next
+        }).collect();
+        // a full set is only kept until every predecessor has been built from it
+        let mut users = vec![0; components.len()];
+        successors.iter().flatten().for_each(|n| users[*n] += 1);
+        let mut full: Vec<Option<Tiles>> = vec![None; components.len()];
+        let mut counts = vec![0; components.len()];
+        // components come out in reverse topological order, so successors are always done first
+        for (c, members) in components.iter().enumerate() {
+            let mut tiles = tiles_new(width, height);
+            members.iter().flat_map(|m| &segments[*m]).for_each(|i| tiles_insert(&mut tiles, *i));
+            successors[c].iter().for_each(|n| tiles_union(&mut tiles, full[*n].as_ref().unwrap()));
+            let base = successors[c].iter().copied().max_by_key(|n| counts[*n]);
+            graph.extra.push(tiles_difference(&tiles, base.and_then(|b| full[b].as_ref())));
+            graph.base.push(base);
+            for n in &successors[c] {
+                users[*n] -= 1;
+                if users[*n] == 0 {
+                    full[*n] = None;
+                }
+            }
+            counts[c] = tiles_count(&tiles);
+            if users[c] > 0 {
+                full[c] = Some(tiles);
+            }
+        }
+        graph.component_of = component_of;
+        graph
+    }
+
+    fn index(&self, (y, x): (isize, isize)) -> usize {
+        y as usize * self.width + x as usize
+    }
+
+    // follows a beam until it leaves the contraption, reaches a node or loops,
+    // returning the indices of the tiles passed and the node reached
+    fn trace(&self, start: Beam, contraption: &Contraption) -> (Vec<usize>, Vec<usize>) {
+        let mut tiles = vec![];
+        let mut seen = HashSet::new();
+        let mut beam = start;
+        loop {
+            if !contraption.contains(beam.position) {
+                return (tiles, vec![]);
+            }
+            if let Some(id) = self.nodes.get(&beam) {
+                return (tiles, vec![*id]);
+            }
+            if !seen.insert(beam) {
+                return (tiles, vec![]);
+            }
+            tiles.push(self.index(beam.position));
+            beam = contraption.step(beam)[0];
+        }
+    }
+
+    pub fn energized_count(&self, start: Beam, contraption: &Contraption) -> usize {
+        let (path, next) = self.trace(start, contraption);
+        let mut tiles = tiles_new(self.width, self.height);
+        path.iter().for_each(|i| tiles_insert(&mut tiles, *i));
+        for id in next {
+            let mut component = Some(self.component_of[id]);
+            while let Some(c) = component {
+                self.extra[c].iter().for_each(|i| tiles_insert(&mut tiles, *i));
+                component = self.base[c];
+            }
         }
+        tiles_count(&tiles)
     }
-    iproduct!(0..energized.len(), 0..energized[0].len())
-            .filter(|(y, x)| energized[*y][*x].len() > 0).count()
 }
 
+// Tarjan's algorithm, components are returned in reverse topological order
+fn strongly_connected(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        edges: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        lowlink: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next_index: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, v: usize) {
+            self.index[v] = Some(self.next_index);
+            self.lowlink[v] = self.next_index;
+            self.next_index += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+            for &w in &self.edges[v] {
+                match self.index[w] {
+                    None => {
+                        self.visit(w);
+                        self.lowlink[v] = self.lowlink[v].min(self.lowlink[w]);
+                    },
+                    Some(i) if self.on_stack[w] => self.lowlink[v] = self.lowlink[v].min(i),
+                    _ => (),
+                }
+            }
+            if Some(self.lowlink[v]) == self.index[v] {
+                let mut component = vec![];
+                while let Some(w) = self.stack.pop() {
+                    self.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    let n = edges.len();
+    let mut tarjan = Tarjan {
+        edges, index: vec![None; n], lowlink: vec![0; n], on_stack: vec![false; n],
+        stack: vec![], next_index: 0, components: vec![],
+    };
+    for v in 0..n {
+        if tarjan.index[v].is_none() {
+            tarjan.visit(v);
+        }
+    }
+    tarjan.components
+}
+
+// energized tile count for every edge entry
+pub fn edge_heat_map(contraption: &Contraption) -> Vec<(Beam, usize)> {
+    let graph = BeamGraph::new(contraption);
+    edge_entries(contraption).into_iter().map(|b| (b, graph.energized_count(b, contraption))).collect()
+}
+
+// the contraption framed by the edge entry counts, shaded from ' ' (fewest) to '@' (most)
+pub fn render_heat_map(contraption: &Contraption, heat: &[(Beam, usize)]) -> String {
+    const SHADES: &[u8] = b" .:-=+*#%@";
+    let (height, width) = (contraption.height(), contraption.width());
+    let (min, max) = (heat.iter().map(|h| h.1).min().unwrap(), heat.iter().map(|h| h.1).max().unwrap());
+    let mut frame = vec![vec![' '; width + 2]; height + 2];
+    for (y, row) in contraption.tiles.iter().enumerate() {
+        for (x, c) in row.iter().enumerate() {
+            frame[y + 1][x + 1] = *c;
+        }
+    }
+    for (beam, count) in heat {
+        let (y, x) = (beam.position.0 as usize + 1, beam.position.1 as usize + 1);
+        let (fy, fx) = match beam.moving {
+            Moving::Right => (y, 0),
+            Moving::Left => (y, width + 1),
+            Moving::Down => (0, x),
+            Moving::Up => (height + 1, x),
+        };
+        let level = if max == min { SHADES.len() - 1 } else { (count - min) * (SHADES.len() - 1) / (max - min) };
+        frame[fy][fx] = SHADES[level] as char;
+    }
+    frame.iter().map(|row| row.iter().collect::<String>()).collect::<Vec<_>>().join("\n")
+}
+
+/*
+ Solution command:
+    cargo run -- day16 data/day16.txt [show] [extended]
+*/
 
 pub fn day16(args: &[String]) {
     println!("Day 16");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args[1..].iter().any(|a| a != "show" && a != "extended") {
+        println!("Missing input file, optional 'show' and 'extended'");
         return;
     }
+    let show = args[1..].iter().any(|a| a == "show");
+    let elements = if args[1..].iter().any(|a| a == "extended") { Elements::extended() } else { Elements::standard() };
     let filename = &args[0];
     println!("In file {}", filename);
     let contents = fs::read_to_string(filename)
         .expect("Something went wrong reading the file");
 
-    let contraption: Vec<Vec<_>> = contents.lines()
-        .map(|l| l.chars().collect())
-        .collect();
+    let contraption = match elements.parse(&contents) {
+        Ok(contraption) => contraption,
+        Err(e) => {
+            println!("Invalid contraption: {}", e);
+            return;
+        }
+    };
 
     // Part 1
-    let part1 = move_beam(Beam::new((0,0), Moving::Right), &contraption);
-    println!("Part 1: {}", part1);
+    let energized = move_beam(Beam::new((0,0), Moving::Right), &contraption);
+    if show {
+        println!("{}", energized.render(&contraption));
+    }
+    println!("Part 1: {}", energized.count());
 
     // Part 2
-    let mut beams: Vec<Beam> = vec![];
-    beams.append(&mut (0..contraption.len()).map(|y| Beam::new((y as isize, 0), Moving::Right)).collect());
-    beams.append(&mut (0..contraption.len()).map(|y| Beam::new((y as isize, contraption[y].len() as isize - 1), Moving::Left)).collect());
-    beams.append(&mut (0..contraption[0].len()).map(|x| Beam::new((0, x as isize), Moving::Down)).collect());
-    beams.append(&mut (0..contraption[0].len()).map(|x| Beam::new((contraption.len() as isize - 1, x as isize), Moving::Up)).collect());
-    println!("Part 2: {}", beams.iter().map(|b| move_beam(*b, &contraption)).max().unwrap());
+    let heat = edge_heat_map(&contraption);
+    if show {
+        println!("{}", render_heat_map(&contraption, &heat));
+        let (beam, count) = heat.iter().max_by_key(|h| h.1).unwrap();
+        println!("Best entry: {:?} moving {:?} energizes {}", beam.position, beam.moving, count);
+    }
+    println!("Part 2: {}", heat.iter().map(|h| h.1).max().unwrap());
 }
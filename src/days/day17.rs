@@ -2,6 +2,7 @@
 use std::fs;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::iter::zip;
 
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -21,12 +22,79 @@ fn moving_index(moving: Moving) -> usize {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
+fn opposite(moving: Moving) -> Moving {
+    match moving {
+        Moving::Right => Moving::Left,
+        Moving::Left => Moving::Right,
+        Moving::Up => Moving::Down,
+        Moving::Down => Moving::Up,
+    }
+}
+
+// how many blocks a crucible must and may move in a straight line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crucible {
+    min_run: u32,
+    max_run: u32,
+    // whether the crucible must have moved `min_run` blocks before it can stop at the end
+    end_requires_min: bool,
+}
+
+impl Crucible {
+    pub const NORMAL: Crucible = Crucible { min_run: 1, max_run: 3, end_requires_min: false };
+    pub const ULTRA: Crucible = Crucible { min_run: 4, max_run: 10, end_requires_min: true };
+
+    pub fn new(min_run: u32, max_run: u32, end_requires_min: bool) -> Result<Self, String> {
+        if max_run < 1 || max_run < min_run {
+            return Err(format!("Max run {} must be at least 1 and at least min run {}", max_run, min_run));
+        }
+        Ok(Self { min_run: min_run.max(1), max_run, end_requires_min })
+    }
+
+    pub fn min_run(&self) -> u32 {
+        self.min_run
+    }
+
+    pub fn max_run(&self) -> u32 {
+        self.max_run
+    }
+
+    pub fn end_requires_min(&self) -> bool {
+        self.end_requires_min
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 struct State {
     heatloss: u32,
-    path: Vec<(isize, isize)>,
+    position: (isize, isize),
     moving: Moving,
-    moving_count: u32
+    // blocks moved in a straight line, including the last one
+    run: u32
+}
+
+// (y, x, moving index, run - 1)
+type Key = (usize, usize, usize, usize);
+
+impl State {
+    fn key(&self) -> Key {
+        (self.position.0 as usize, self.position.1 as usize, moving_index(self.moving), self.run as usize - 1)
+    }
+}
+
+// the state each state was reached from
+type Previous = Vec<Vec<Vec<Vec<Option<Key>>>>>;
+
+// the path back to the first move, the start itself is not included
+fn recover_path(previous: &Previous, end: Key) -> Vec<(isize, isize)> {
+    let mut path = vec![(end.0 as isize, end.1 as isize)];
+    let mut key = end;
+    while let Some(prev) = previous[key.0][key.1][key.2][key.3] {
+        path.push((prev.0 as isize, prev.1 as isize));
+        key = prev;
+    }
+    path.reverse();
+    path
 }
 
 // The priority queue depends on `Ord`.
@@ -38,9 +106,9 @@ impl Ord for State {
         // In case of a tie we compare positions - this step is necessary
         // to make implementations of `PartialEq` and `Ord` consistent.
         other.heatloss.cmp(&self.heatloss)
-            .then_with(|| self.path.last().unwrap().cmp(&other.path.last().unwrap()))
+            .then_with(|| self.position.cmp(&other.position))
             .then_with(|| moving_index(self.moving).cmp(&moving_index(other.moving)))
-            .then_with(|| self.moving_count.cmp(&other.moving_count))
+            .then_with(|| self.run.cmp(&other.run))
     }
 }
 
@@ -51,10 +119,17 @@ impl PartialOrd for State {
     }
 }
 
+/*
+ Solution command:
+    cargo run -- day17 data/day17.txt [min run] [max run] [any] [show]
+*/
+
 pub fn day17(args: &[String]) {
     println!("Day 17");
-    if args.len() != 1 {
-        println!("Missing input file.");
+    let show = args.iter().skip(1).any(|a| a == "show");
+    let args: Vec<_> = args.iter().filter(|a| *a != "show").cloned().collect();
+    if args.is_empty() || args.len() == 2 || args.len() > 4 || (args.len() == 4 && args[3] != "any") {
+        println!("Missing input file, optional min and max run, 'any' to stop at the end before min run and 'show'.");
         return;
     }
     let filename = &args[0];
@@ -67,11 +142,70 @@ pub fn day17(args: &[String]) {
         .collect();
 
     let end = (map.len() as isize - 1, map[0].len() as isize - 1);
-    let part1 = shortest_path(&map, end);
-    println!("Part 1: {}", part1);
+    let mut crucibles = vec![("Part 1".to_string(), Crucible::NORMAL), ("Part 2".to_string(), Crucible::ULTRA)];
+    if args.len() >= 3 {
+        let crucible = match (args[1].parse(), args[2].parse()) {
+            (Ok(min_run), Ok(max_run)) => Crucible::new(min_run, max_run, args.len() == 3),
+            _ => Err(format!("Invalid run lengths {} and {}", args[1], args[2])),
+        };
+        let crucible = match crucible {
+            Ok(crucible) => crucible,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        crucibles.push((format!("Crucible run {} to {}{}", crucible.min_run(), crucible.max_run(),
+            if crucible.end_requires_min() { "" } else { ", any stop" }), crucible));
+    }
+    for (name, crucible) in crucibles {
+        let (heatloss, path) = match shortest_path(&map, (0, 0), &[end], &crucible) {
+            Ok(found) => found,
+            Err(e) => {
+                println!("{}: {}", name, e);
+                continue;
+            }
+        };
+        if show {
+            println!("{}", render_path(&map, &path));
+            let steps: Vec<_> = zip(path.iter().skip(1), cumulative_heatloss(&map, &path).iter().skip(1))
+                .map(|((y, x), h)| format!("({},{}) {}", y, x, h))
+                .collect();
+            println!("{}", steps.join(", "));
+        }
+        println!("{}: {}", name, heatloss);
+    }
+}
+
+// the heat map with each block of the path after the start replaced by the
+// direction the crucible moved to enter it
+pub fn render_path(map: &[Vec<u32>], path: &[(isize, isize)]) -> String {
+    let mut tiles: Vec<Vec<char>> = map.iter()
+        .map(|row| row.iter().map(|h| char::from_digit(*h, 10).unwrap()).collect())
+        .collect();
+    for step in path.windows(2) {
+        let ((y0, x0), (y1, x1)) = (step[0], step[1]);
+        tiles[y1 as usize][x1 as usize] = match (y1 - y0, x1 - x0) {
+            (0, 1) => '>',
+            (0, -1) => '<',
+            (-1, 0) => '^',
+            (1, 0) => 'v',
+            _ => unreachable!()
+        };
+    }
+    tiles.iter().map(|row| row.iter().collect::<String>()).collect::<Vec<_>>().join("\n")
+}
 
-    let part2 = shortest_path2(&map, end);
-    println!("Part 2: {}", part2);
+// total heat loss on arriving at each block of the path, the start block is free
+pub fn cumulative_heatloss(map: &[Vec<u32>], path: &[(isize, isize)]) -> Vec<u32> {
+    path.iter().enumerate()
+        .scan(0, |total, (i, p)| {
+            if i > 0 {
+                *total += get_value(map, *p);
+            }
+            Some(*total)
+        })
+        .collect()
 }
 
 lazy_static! {
@@ -84,7 +218,7 @@ pub fn adjacent(pos: (isize, isize)) -> Vec<(isize, isize, Moving)> {
     ADJ.iter().map(|(dy, dx, moving)| (pos.0 + dy, pos.1 + dx, *moving)).collect()
 }
 
-pub fn get_value(map: &Vec<Vec<u32>>, pos: (isize, isize)) -> u32 {
+pub fn get_value(map: &[Vec<u32>], pos: (isize, isize)) -> u32 {
     let (my, mx) = (map.len(), map[0].len());
     if pos.0 >= 0 && pos.1 >= 0 && pos.0 < my as isize && pos.1 < mx as isize {
         return map[pos.0 as usize][pos.1 as usize];
@@ -92,136 +226,118 @@ pub fn get_value(map: &Vec<Vec<u32>>, pos: (isize, isize)) -> u32 {
     u32::MAX
 }
 
-fn shortest_path(map: &Vec<Vec<u32>>, end: (isize, isize)) -> u32 {
-    let (ysize, xsize) = (map.len(), map[0].len());
-    let mut lowest = vec![vec![vec![vec![u32::MAX; 3]; 4]; xsize]; ysize];
+impl Crucible {
+    fn can_stop(&self, run: u32) -> bool {
+        !self.end_requires_min || run >= self.min_run
+    }
+}
+
+// lowest heat loss found for every state
+type Lowest = Vec<Vec<Vec<Vec<u32>>>>;
+
+// Dijkstra from `start`, stopping early at the first of `goals` the crucible can stop at
+fn search(map: &[Vec<u32>], start: (isize, isize), goals: &[(isize, isize)], crucible: &Crucible)
+        -> (Lowest, Previous, Option<(u32, Key)>) {
+    let (ysize, xsize, runs) = (map.len(), map[0].len(), crucible.max_run as usize);
+    let mut lowest: Lowest = vec![vec![vec![vec![u32::MAX; runs]; 4]; xsize]; ysize];
+    let mut previous: Previous = vec![vec![vec![vec![None; runs]; 4]; xsize]; ysize];
 
+    // the first move can go in any direction
     let mut paths = BinaryHeap::new();
-    paths.push(State {
-        heatloss: 0,
-        path: vec![(0, 0)],
-        moving: Moving::Up, // don't use right or down
-        moving_count: 0,
-    });
-
-    // let mut best_path = vec![];
-    let mut best = u32::MAX;
-    while let Some(current) = paths.pop() {
-        let (heatloss, path, moving, moving_count) = (current.heatloss, current.path, current.moving, current.moving_count);
-        // out of bounds
-        if get_value(map, *path.last().unwrap()) == u32::MAX {
+    for (y, x, m) in adjacent(start) {
+        let adj_heatloss = get_value(map, (y, x));
+        if adj_heatloss == u32::MAX {
             continue;
         }
-        assert!(heatloss == path.iter().skip(1).map(|p| get_value(map, *p)).sum());
-        let (cur_y, cur_x) = *path.last().unwrap();
-        // at end
-        if (cur_y, cur_x) == end {
-            best = heatloss;
-            // best_path = path.clone();
-            break;
-        }
-        if heatloss > best || heatloss > lowest[cur_y as usize][cur_x as usize][moving_index(moving)][moving_count as usize] { 
-            continue;
-        }
-        // println!("{:?} {} {:?} {}", path.last().unwrap(), heatloss, moving, moving_count);
-        
-        for (y, x, m) in adjacent(*path.last().unwrap()) {
-            // detect loops
-            if path.contains(&(y, x)) {
-                continue;
-            }
-            let adj_heatloss = get_value(&map, (y, x));
-            if adj_heatloss == u32::MAX {
-                continue;
-            }
-            let next_heatloss = heatloss + adj_heatloss;
-            let next_moving_index = moving_index(m);
-            let next_moving_count = if moving == m { moving_count + 1 } else { 0 };
-            // three steps in the same direction
-            if next_moving_count >= 3 {
-                continue;
-            }
-            if next_heatloss < lowest[y as usize][x as usize][next_moving_index][next_moving_count as usize] {
-                let mut next_path = path.clone();
-                next_path.push((y, x));
-                paths.push(State {
-                    heatloss: next_heatloss,
-                    path: next_path,
-                    moving: m,
-                    moving_count: next_moving_count
-                });
-                lowest[y as usize][x as usize][next_moving_index][next_moving_count as usize] = next_heatloss;
-            }
-        }
+        let state = State { heatloss: adj_heatloss, position: (y, x), moving: m, run: 1 };
+        let (ky, kx, km, kr) = state.key();
+        lowest[ky][kx][km][kr] = adj_heatloss;
+        paths.push(state);
     }
-    best
-}
 
-fn shortest_path2(map: &Vec<Vec<u32>>, end: (isize, isize)) -> u32 {
-    let (ysize, xsize) = (map.len(), map[0].len());
-    let mut lowest = vec![vec![vec![vec![u32::MAX; 10]; 4]; xsize]; ysize];
-
-    let mut paths = BinaryHeap::new();
-    paths.push(State {
-        heatloss: 0,
-        path: vec![(0, 0)],
-        moving: Moving::Up, // don't use right or down
-        moving_count: 4,
-    });
-
-    // let mut best_path = vec![];
-    let mut best = u32::MAX;
     while let Some(current) = paths.pop() {
-        let (heatloss, path, moving, moving_count) = (current.heatloss, current.path, current.moving, current.moving_count);
-        // out of bounds
-        if get_value(map, *path.last().unwrap()) == u32::MAX {
+        let (heatloss, moving, run) = (current.heatloss, current.moving, current.run);
+        let (cur_y, cur_x, cur_m, cur_r) = current.key();
+        if heatloss > lowest[cur_y][cur_x][cur_m][cur_r] {
             continue;
         }
-        assert!(heatloss == path.iter().skip(1).map(|p| get_value(map, *p)).sum());
-        let (cur_y, cur_x) = *path.last().unwrap();
-        // at end
-        if (cur_y, cur_x) == end {
-            best = heatloss;
-            // best_path = path.clone();
-            break;
+        // at a goal
+        if goals.contains(&current.position) && crucible.can_stop(run) {
+            return (lowest, previous, Some((heatloss, current.key())));
         }
-        if heatloss > best || heatloss > lowest[cur_y as usize][cur_x as usize][moving_index(moving)][moving_count as usize] { 
-            continue;
-        }
-        // println!("{:?} {} {:?} {}", path.last().unwrap(), heatloss, moving, moving_count);
-        
-        for (y, x, m) in adjacent(*path.last().unwrap()) {
-            // detect loops
-            if path.contains(&(y, x)) {
+
+        for (y, x, m) in adjacent(current.position) {
+            // no turning back
+            if m == opposite(moving) {
                 continue;
             }
-            let adj_heatloss = get_value(&map, (y, x));
-            if adj_heatloss == u32::MAX {
+            // can't change direction before moving min run blocks in the same direction
+            if m != moving && run < crucible.min_run {
                 continue;
             }
-            let next_heatloss = heatloss + adj_heatloss;
-            let next_moving_index = moving_index(m);
-            let next_moving_count = if moving == m { moving_count + 1 } else { 0 };
-            // 10 steps in the same direction
-            if next_moving_count >= 10 {
+            let next_run = if moving == m { run + 1 } else { 1 };
+            // no more than max run blocks in the same direction
+            if next_run > crucible.max_run {
                 continue;
             }
-            // can't change direction if haven't moved 4 blocks in the same direction
-            if moving_count < 3 && moving != m {
-                continue;  // can't switch directions yet
+            let adj_heatloss = get_value(map, (y, x));
+            if adj_heatloss == u32::MAX {
+                continue;
             }
-            if next_heatloss < lowest[y as usize][x as usize][next_moving_index][next_moving_count as usize] {
-                let mut next_path = path.clone();
-                next_path.push((y, x));
-                paths.push(State {
-                    heatloss: next_heatloss,
-                    path: next_path,
-                    moving: m,
-                    moving_count: next_moving_count
-                });
-                lowest[y as usize][x as usize][next_moving_index][next_moving_count as usize] = next_heatloss;
+            let next = State {
+                heatloss: heatloss + adj_heatloss,
+                position: (y, x),
+                moving: m,
+                run: next_run
+            };
+            let (ny, nx, nm, nr) = next.key();
+            if next.heatloss < lowest[ny][nx][nm][nr] {
+                lowest[ny][nx][nm][nr] = next.heatloss;
+                previous[ny][nx][nm][nr] = Some(current.key());
+                paths.push(next);
             }
         }
     }
-    best
+    (lowest, previous, None)
+}
+
+fn on_map(map: &[Vec<u32>], (y, x): (isize, isize)) -> bool {
+    y >= 0 && x >= 0 && map.get(y as usize).is_some_and(|row| (x as usize) < row.len())
+}
+
+fn check_on_map(map: &[Vec<u32>], what: &str, pos: (isize, isize)) -> Result<(), String> {
+    if on_map(map, pos) { Ok(()) } else { Err(format!("{} {:?} is not on the map", what, pos)) }
+}
+
+// lowest heat loss from `start` to the nearest of `goals` and the path taken,
+// an error when a position is off the map or no goal can be reached
+pub fn shortest_path(map: &[Vec<u32>], start: (isize, isize), goals: &[(isize, isize)], crucible: &Crucible)
+        -> Result<(u32, Vec<(isize, isize)>), String> {
+    check_on_map(map, "Start", start)?;
+    goals.iter().try_for_each(|goal| check_on_map(map, "Goal", *goal))?;
+    if goals.contains(&start) {
+        return Ok((0, vec![start]));
+    }
+    match search(map, start, goals, crucible) {
+        (_, previous, Some((heatloss, key))) => {
+            let mut path = recover_path(&previous, key);
+            path.insert(0, start);
+            Ok((heatloss, path))
+        },
+        _ => Err("No goal can be reached".to_string()),
+    }
+}
+
+// lowest heat loss from `start` to every block the crucible can stop at, u32::MAX if none
+pub fn distance_field(map: &[Vec<u32>], start: (isize, isize), crucible: &Crucible) -> Result<Vec<Vec<u32>>, String> {
+    check_on_map(map, "Start", start)?;
+    let (lowest, _, _) = search(map, start, &[], crucible);
+    let mut field: Vec<Vec<u32>> = lowest.iter().map(|row| row.iter().map(|cell|
+        cell.iter().flat_map(|runs| runs.iter().enumerate()
+            .filter(|(r, _)| crucible.can_stop(*r as u32 + 1))
+            .map(|(_, h)| *h))
+        .min().unwrap_or(u32::MAX)
+    ).collect()).collect();
+    field[start.0 as usize][start.1 as usize] = 0;
+    Ok(field)
 }
@@ -1,65 +1,111 @@
 use std::fs;
 use regex::Regex;
 use std::str::FromStr;
-use std::num::ParseIntError;
+use std::fmt;
 use geo::{Area, Polygon, EuclideanLength};
 use geo::geometry::LineString;
 
 
+// a dig plan line, the visible instruction with its trench color
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Dig {
-    dir: char,
-    distance: isize,
-    // color: (u32, u32, u32)
+    pub dir: char,
+    pub distance: isize,
+    pub color: (u8, u8, u8)
 }
 
+// problems found loading a dig plan, lines and steps are numbered from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanError {
+    // line is 0 when a single Dig is parsed on its own
+    InvalidLine { line: usize, text: String },
+    EmptyPlan,
+    EmptyMove { step: usize },
+    // step is 0 when a single Dig is converted on its own
+    InvalidColorDirection { step: usize, digit: u8 },
+    NotClosed { end: (isize, isize) },
+    // a step digging straight back along the previous one
+    Backtrack { step: usize },
+    // two steps whose trenches cross or touch
+    Intersects { first: usize, second: usize },
+    // the plan's bounding box has more cells than allowed
+    TooLarge { cells: u128, max_cells: usize },
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanError::InvalidLine { line, text } => write!(f, "invalid dig plan line {}: {:?}", line, text),
+            PlanError::EmptyPlan => write!(f, "plan has no steps"),
+            PlanError::EmptyMove { step } => write!(f, "step {} does not move", step),
+            PlanError::InvalidColorDirection { step, digit } =>
+                write!(f, "step {} has color direction digit {:x}, expected 0 to 3", step, digit),
+            PlanError::NotClosed { end } => write!(f, "plan ends at {:?} instead of the start", end),
+            PlanError::Backtrack { step } => write!(f, "step {} digs back along step {}", step, step - 1),
+            PlanError::Intersects { first, second } => write!(f, "trenches of steps {} and {} cross or touch", first, second),
+            PlanError::TooLarge { cells, max_cells } => write!(f, "plan spans {} cells, more than {}", cells, max_cells),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
 impl FromStr for Dig {
-    type Err = ParseIntError;
+    type Err = PlanError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"([LRUD])\s(\d+)\s\(#([0-9a-f]{2})([0-9a-f]{2})([0-9a-f]{2})\)").unwrap();
+            static ref RE: Regex = Regex::new(r"^([LRUD])\s(\d+)\s\(#([0-9a-f]{2})([0-9a-f]{2})([0-9a-f]{2})\)$").unwrap();
         }
-        let caps = RE.captures(s).unwrap();
+        let invalid = || PlanError::InvalidLine { line: 0, text: s.to_string() };
+        let caps = RE.captures(s.trim()).ok_or_else(invalid)?;
         Ok(Self {
             dir: caps[1].chars().next().unwrap(),
-            distance: caps[2].parse().unwrap(),
-            // color: (
-            //     u32::from_str_radix(&caps[3], 16).unwrap(),
-            //     u32::from_str_radix(&caps[4], 16).unwrap(),
-            //     u32::from_str_radix(&caps[5], 16).unwrap()
-            // )
+            distance: caps[2].parse().map_err(|_| invalid())?,
+            color: (
+                u8::from_str_radix(&caps[3], 16).unwrap(),
+                u8::from_str_radix(&caps[4], 16).unwrap(),
+                u8::from_str_radix(&caps[5], 16).unwrap()
+            )
         })
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Dig2 {
-    dir: char,
-    distance: isize,
+pub fn load_plan(contents: &str) -> Result<Vec<Dig>, PlanError> {
+    contents.lines().enumerate()
+        .map(|(i, l)| l.parse().map_err(|_| PlanError::InvalidLine { line: i + 1, text: l.to_string() }))
+        .collect()
 }
 
-impl FromStr for Dig2 {
-    type Err = ParseIntError;
+impl Dig {
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"([LRUD])\s(\d+)\s\(#([0-9a-f]{5})([0-9a-f]{1})\)").unwrap();
-        }
-        let caps = RE.captures(s).unwrap();
-        Ok(Self {
-            dir: match caps[4].chars().next().unwrap() {
-                '0' => 'R',
-                '1' => 'D',
-                '2' => 'L',
-                '3' => 'U',
-                _ => unreachable!()
-            },
-            distance: isize::from_str_radix(&caps[3], 16).unwrap(),
-        })
+    pub fn instruction(&self) -> (char, isize) {
+        (self.dir, self.distance)
+    }
+
+    // the real instruction hidden in the color: five hex digits of distance then the direction
+    pub fn color_instruction(&self) -> Result<(char, isize), PlanError> {
+        let (r, g, b) = self.color;
+        let dir = match b & 0xf {
+            0 => 'R',
+            1 => 'D',
+            2 => 'L',
+            3 => 'U',
+            digit => return Err(PlanError::InvalidColorDirection { step: 0, digit })
+        };
+        Ok((dir, ((r as isize) << 12) | ((g as isize) << 4) | (b as isize >> 4)))
     }
 }
 
+pub fn color_instructions(plan: &[Dig]) -> Result<Vec<(char, isize)>, PlanError> {
+    plan.iter().enumerate()
+        .map(|(i, dig)| dig.color_instruction().map_err(|e| match e {
+            PlanError::InvalidColorDirection { digit, .. } => PlanError::InvalidColorDirection { step: i + 1, digit },
+            e => e,
+        }))
+        .collect()
+}
+
 lazy_static! {
     static ref ADJ: Vec<(isize, isize)> = vec![
         (0, 1), (0, -1), (1, 0), (-1, 0)
@@ -70,17 +116,36 @@ pub fn adjacent(pos: (isize, isize)) -> Vec<(isize, isize)> {
     ADJ.iter().map(|(dy, dx)| (pos.0 + dy, pos.1 + dx)).collect()
 }
 
-pub fn polygon_area(vertices: Vec<(f64, f64)>) -> usize {
-    let ls = LineString::from(vertices);
+// cells dug out, by Pick's theorem the interior cells are the shoelace area
+// less half the boundary plus 1, then the boundary cells are added back
+pub fn polygon_area(vertices: &[(isize, isize)]) -> i128 {
+    let (twice_area, boundary) = vertices.windows(2).fold((0i128, 0i128), |(area, edges), v| {
+        let ((y0, x0), (y1, x1)) = ((v[0].0 as i128, v[0].1 as i128), (v[1].0 as i128, v[1].1 as i128));
+        (area + x0 * y1 - x1 * y0, edges + (y1 - y0).abs() + (x1 - x0).abs())
+    });
+    twice_area.abs() / 2 + boundary / 2 + 1
+}
+
+// floating point version of polygon_area using geo, kept to cross-check it
+pub fn polygon_area_geo(vertices: &[(isize, isize)]) -> usize {
+    let ls = LineString::from(vertices.iter().map(|(y, x)| (*y as f64, *x as f64)).collect::<Vec<_>>());
     let edgelen = ls.euclidean_length();  // outer edge count
     let polygon = Polygon::new(ls, vec![]);
     edgelen as usize / 2 + 1 + polygon.unsigned_area() as usize
 }
 
-pub fn to_vertices(moves: &Vec<(char, isize)>) -> Vec<(f64, f64)> {
+// the corners of the trench, starting and ending at (0, 0), checked to form a
+// closed loop that never crosses or touches itself
+pub fn to_vertices(moves: &[(char, isize)]) -> Result<Vec<(isize, isize)>, PlanError> {
     let mut pos: (isize, isize) = (0, 0);
-    let mut vertices = vec![pos]; 
-    vertices.append(&mut moves.iter().map(|(dir, d)| {
+    let mut vertices = vec![pos];
+    if moves.is_empty() {
+        return Err(PlanError::EmptyPlan);
+    }
+    for (step, (dir, d)) in moves.iter().enumerate() {
+        if *d == 0 {
+            return Err(PlanError::EmptyMove { step: step + 1 });
+        }
         pos = match dir {
             'R' => (pos.0, pos.1 + d),
             'L' => (pos.0, pos.1 - d),
@@ -88,17 +153,171 @@ pub fn to_vertices(moves: &Vec<(char, isize)>) -> Vec<(f64, f64)> {
             'U' => (pos.0 - d, pos.1),
             _ => unreachable!()
         };
-        pos
-    }).collect());
-    // println!("{:?} {}", vertices, vertices.len());
-    assert!(vertices.first().unwrap() == vertices.last().unwrap());
-    vertices.into_iter().map(|(y, x)| (y as f64, x as f64)).collect()
+        vertices.push(pos);
+    }
+    if vertices.first() != vertices.last() {
+        return Err(PlanError::NotClosed { end: pos });
+    }
+    check_crossings(&vertices)?;
+    Ok(vertices)
+}
+
+// Every pair of trench segments is compared. Neighbouring segments share their
+// common corner, so they only clash when the second one turns straight back.
+fn check_crossings(vertices: &[(isize, isize)]) -> Result<(), PlanError> {
+    let segments: Vec<_> = vertices.windows(2).map(|v| (v[0], v[1])).collect();
+    let n = segments.len();
+    let reverses = |a: ((isize, isize), (isize, isize)), b: ((isize, isize), (isize, isize))| {
+        let (da, db) = ((a.1.0 - a.0.0, a.1.1 - a.0.1), (b.1.0 - b.0.0, b.1.1 - b.0.1));
+        (da.0 == 0) == (db.0 == 0) && da.0.signum() * db.0.signum() + da.1.signum() * db.1.signum() < 0
+    };
+    for i in 0..n {
+        for j in i + 1..n {
+            let neighbours = j == i + 1 || (i == 0 && j == n - 1);
+            if neighbours {
+                let (first, second) = if j == i + 1 { (i, j) } else { (j, i) };
+                if reverses(segments[first], segments[second]) {
+                    return Err(PlanError::Backtrack { step: second + 1 });
+                }
+            } else if overlaps(segments[i], segments[j]) {
+                return Err(PlanError::Intersects { first: i + 1, second: j + 1 });
+            }
+        }
+    }
+    Ok(())
+}
+
+// whether two axis aligned segments share any point
+fn overlaps(a: ((isize, isize), (isize, isize)), b: ((isize, isize), (isize, isize))) -> bool {
+    let span = |s: ((isize, isize), (isize, isize))| (
+        (s.0.0.min(s.1.0), s.0.0.max(s.1.0)),
+        (s.0.1.min(s.1.1), s.0.1.max(s.1.1)),
+    );
+    let ((ay, ax), (by, bx)) = (span(a), span(b));
+    ay.0 <= by.1 && by.0 <= ay.1 && ax.0 <= bx.1 && bx.0 <= ax.1
+}
+
+// cells dug out counted on a grid by flood filling the outside, None for plans
+// spanning more than `max_cells` cells
+pub fn flood_fill_area(vertices: &[(isize, isize)], max_cells: usize) -> Option<usize> {
+    let (ymin, ymax) = (vertices.iter().map(|v| v.0).min()?, vertices.iter().map(|v| v.0).max()?);
+    let (xmin, xmax) = (vertices.iter().map(|v| v.1).min()?, vertices.iter().map(|v| v.1).max()?);
+    let (height, width) = ((ymax - ymin + 1) as usize, (xmax - xmin + 1) as usize);
+    if height.checked_mul(width)? > max_cells {
+        return None;
+    }
+    let mut trench = vec![vec![false; width]; height];
+    for v in vertices.windows(2) {
+        let (mut y, mut x) = v[0];
+        let (dy, dx) = ((v[1].0 - y).signum(), (v[1].1 - x).signum());
+        while (y, x) != v[1] {
+            trench[(y - ymin) as usize][(x - xmin) as usize] = true;
+            (y, x) = (y + dy, x + dx);
+        }
+    }
+    let inside = fill_inside(&trench);
+    Some(trench.iter().chain(inside.iter()).flatten().filter(|c| **c).count())
+}
+
+// largest plan, by bounding box, that is laid out on a grid
+const MAX_GRID_CELLS: usize = 10_000_000;
+
+fn checked_area(vertices: &[(isize, isize)]) -> i128 {
+    let area = polygon_area(vertices);
+    let geo_area = polygon_area_geo(vertices);
+    if area != geo_area as i128 {
+        println!("Warning: geo area {} differs from {}", geo_area, area);
+    }
+    if let Some(fill_area) = flood_fill_area(vertices, MAX_GRID_CELLS) {
+        if area != fill_area as i128 {
+            println!("Warning: flood fill area {} differs from {}", fill_area, area);
+        }
+    }
+    area
+}
+
+// binary PPM of the lagoon, trench cells in their color, the interior grey;
+// the visible instructions must form a valid plan spanning at most `max_cells` cells
+pub fn render_ppm(plan: &[Dig], max_cells: usize) -> Result<Vec<u8>, PlanError> {
+    let vertices = to_vertices(&plan.iter().map(Dig::instruction).collect::<Vec<_>>())?;
+    let span = |coord: fn(&(isize, isize)) -> isize| {
+        let (min, max) = (vertices.iter().map(coord).min().unwrap(), vertices.iter().map(coord).max().unwrap());
+        (max as i128 - min as i128 + 1) as u128
+    };
+    let cells = span(|v| v.0) * span(|v| v.1);
+    if cells > max_cells as u128 {
+        return Err(PlanError::TooLarge { cells, max_cells });
+    }
+    let mut pos: (isize, isize) = (0, 0);
+    let mut trench = vec![];
+    for dig in plan {
+        let (dy, dx) = match dig.dir {
+            'R' => (0, 1),
+            'L' => (0, -1),
+            'D' => (1, 0),
+            'U' => (-1, 0),
+            _ => unreachable!()
+        };
+        for _ in 0..dig.distance {
+            pos = (pos.0 + dy, pos.1 + dx);
+            trench.push((pos, dig.color));
+        }
+    }
+    // a valid plan has at least one trench cell
+    let (ymin, ymax) = (trench.iter().map(|t| t.0.0).min().unwrap(), trench.iter().map(|t| t.0.0).max().unwrap());
+    let (xmin, xmax) = (trench.iter().map(|t| t.0.1).min().unwrap(), trench.iter().map(|t| t.0.1).max().unwrap());
+    let (height, width) = ((ymax - ymin + 1) as usize, (xmax - xmin + 1) as usize);
+
+    let mut pixels = vec![vec![None; width]; height];
+    for ((y, x), color) in &trench {
+        pixels[(y - ymin) as usize][(x - xmin) as usize] = Some(*color);
+    }
+    let inside = fill_inside(&pixels.iter().map(|row| row.iter().map(|p| p.is_some()).collect()).collect::<Vec<_>>());
+
+    let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = match pixels[y][x] {
+                Some(color) => color,
+                None if inside[y][x] => (0x40, 0x40, 0x40),
+                None => (0, 0, 0),
+            };
+            image.extend([r, g, b]);
+        }
+    }
+    Ok(image)
 }
 
+// cells enclosed by the `trench` cells, found by flooding the outside from the border
+pub fn fill_inside(trench: &[Vec<bool>]) -> Vec<Vec<bool>> {
+    let (height, width) = (trench.len() as isize, trench[0].len() as isize);
+    let mut outside = vec![vec![false; width as usize]; height as usize];
+    let mut queue: Vec<(isize, isize)> = (0..height).flat_map(|y| [(y, 0), (y, width - 1)])
+        .chain((0..width).flat_map(|x| [(0, x), (height - 1, x)]))
+        .collect();
+    while let Some((y, x)) = queue.pop() {
+        if y < 0 || x < 0 || y >= height || x >= width {
+            continue;
+        }
+        let (uy, ux) = (y as usize, x as usize);
+        if trench[uy][ux] || outside[uy][ux] {
+            continue;
+        }
+        outside[uy][ux] = true;
+        queue.append(&mut adjacent((y, x)));
+    }
+    (0..height as usize).map(|y| (0..width as usize).map(|x| !trench[y][x] && !outside[y][x]).collect()).collect()
+}
+
+/*
+ Solution command:
+    cargo run -- day18 data/day18.txt [lagoon.ppm]
+*/
+
 pub fn day18(args: &[String]) {
     println!("Day 18");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args.len() > 2 {
+        println!("Missing input file, optional output image");
         return;
     }
     let filename = &args[0];
@@ -106,13 +325,33 @@ pub fn day18(args: &[String]) {
     let contents = fs::read_to_string(filename)
         .expect("Something went wrong reading the file");
 
+    let plan = match load_plan(&contents) {
+        Ok(plan) => plan,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
     // Part 1
-    let plan: Vec<Dig> = contents.lines().map(|l| l.parse().unwrap()).collect();
-    let vertices: Vec<_> = to_vertices(&plan.iter().map(| p | (p.dir, p.distance)).collect());
-    println!("Part 1: {}", polygon_area(vertices));
+    match to_vertices(&plan.iter().map(Dig::instruction).collect::<Vec<_>>()) {
+        Ok(vertices) => println!("Part 1: {}", checked_area(&vertices)),
+        Err(e) => println!("Part 1: {}", e),
+    }
 
     // Part 2
-    let plan2: Vec<Dig2> = contents.lines().map(|l| l.parse().unwrap()).collect();
-    let vertices2: Vec<_> = to_vertices(&plan2.iter().map(| p | (p.dir, p.distance)).collect());
-    println!("Part 2: {}", polygon_area(vertices2)); 
+    match color_instructions(&plan).and_then(|moves| to_vertices(&moves)) {
+        Ok(vertices) => println!("Part 2: {}", checked_area(&vertices)),
+        Err(e) => println!("Part 2: {}", e),
+    }
+
+    if args.len() == 2 {
+        match render_ppm(&plan, MAX_GRID_CELLS) {
+            Ok(image) => {
+                fs::write(&args[1], image).expect("Something went wrong writing the image");
+                println!("Lagoon image written to {}", args[1]);
+            },
+            Err(e) => println!("No image: {}", e),
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use std::fs;
 use itertools::Itertools;
 use std::iter::zip;
+use std::ops::RangeInclusive;
 
 
 pub fn day6(args: &[String]) {
@@ -15,20 +16,44 @@ pub fn day6(args: &[String]) {
         .expect("Something went wrong reading the file");
 
     let (time, distance) = contents.lines()
-        .map(|l| l.split(":").skip(1).next().unwrap().split_whitespace()
-            .map(|n| n.parse().unwrap()).collect::<Vec<u32>>())
+        .map(|l| l.split(':').nth(1).unwrap().split_whitespace()
+            .map(|n| n.parse().unwrap()).collect::<Vec<u64>>())
         .next_tuple().unwrap();
 
-    let part1: usize = zip(time.clone(), distance.clone())
-        .map(|(t, d)| (0..t).map(|p| p * (t - p)).filter(|r| r > &d).count())
+    let part1: u64 = zip(time, distance)
+        .map(|(t, d)| winning_count(t, d))
         .product();
     println!("Part 1: {}", part1);
 
     let (time2, distance2) = contents.lines()
-        .map(|l| l.split(":").skip(1).next().unwrap()
-            .split_whitespace().collect::<String>().parse::<usize>().unwrap())
+        .map(|l| l.split(':').nth(1).unwrap()
+            .split_whitespace().collect::<String>().parse::<u64>().unwrap())
         .next_tuple().unwrap();
-   
-    let part2: usize = (0..time2).map(|p| p * (time2 - p)).filter(|r| r > &distance2).count();
-    println!("Part 2: {}", part2);
+
+    println!("Part 2: {}", winning_count(time2, distance2));
+}
+
+// hold times that travel further than `distance` in a race lasting `time`.
+// Holding for p travels p * (time - p), so the winners lie strictly between the
+// roots of p^2 - time * p + distance = 0, symmetric around time / 2.
+pub fn winning_range(time: u64, distance: u64) -> Option<RangeInclusive<u64>> {
+    let (t, d) = (time as u128, distance as u128);
+    let travels = |p: u128| p * (t - p);
+    let discriminant = (t * t).checked_sub(4 * d)?;
+    // the isqrt estimate of the lower root can be off by one either way
+    let mut low = (t - discriminant.isqrt()) / 2;
+    while low > 0 && travels(low - 1) > d {
+        low -= 1;
+    }
+    while low <= t / 2 && travels(low) <= d {
+        low += 1;
+    }
+    if low > t / 2 {
+        return None;
+    }
+    Some(low as u64..=(t - low) as u64)
+}
+
+pub fn winning_count(time: u64, distance: u64) -> u64 {
+    winning_range(time, distance).map_or(0, |r| r.end() - r.start() + 1)
 }
@@ -1,9 +1,8 @@
 use std::fs;
 use std::str::FromStr;
-use std::num::ParseIntError;
 use regex::Regex;
-use itertools::iproduct;
-use lcmx::lcmx;
+use std::fmt;
+use std::collections::HashMap;
 
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -13,13 +12,13 @@ pub struct Node {
 }
 
 impl FromStr for Node {
-    type Err = ParseIntError;
+    type Err = GhostError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"(\w+) = \((\w+), (\w+)\)").unwrap();
+            static ref RE: Regex = Regex::new(r"^(\w+) = \((\w+), (\w+)\)$").unwrap();
         }
-        let caps = RE.captures(s).unwrap();
+        let caps = RE.captures(s.trim()).ok_or_else(|| GhostError::InvalidNode(s.to_string()))?;
         Ok(Self {
             name: caps[1].to_string(),
             instruction: (caps[2].to_string(), caps[3].to_string())
@@ -27,10 +26,301 @@ impl FromStr for Node {
     }
 }
 
+// the network with node names interned, successors[id] holds the left and right node ids
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub instructions: Vec<usize>,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    successors: Vec<[usize; 2]>,
+}
+
+impl FromStr for Network {
+    type Err = GhostError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sections = s.split("\n\n");
+        let instructions = sections.next().unwrap_or("").trim().chars()
+            .map(|c| match c {
+                'L' => Ok(0),
+                'R' => Ok(1),
+                _ => Err(GhostError::InvalidInstruction(c)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if instructions.is_empty() {
+            return Err(GhostError::MissingInstructions);
+        }
+        let nodes: Vec<Node> = sections.next().unwrap_or("").lines()
+                                    .map(| l | l.parse()).collect::<Result<_, _>>()?;
+        let names: Vec<String> = nodes.iter().map(|n| n.name.clone()).collect();
+        let mut ids: HashMap<String, usize> = HashMap::new();
+        for (i, name) in names.iter().enumerate() {
+            if ids.insert(name.clone(), i).is_some() {
+                return Err(GhostError::DuplicateNode(name.clone()));
+            }
+        }
+        let id = |name: &String| ids.get(name).copied().ok_or_else(|| GhostError::UnknownNode(name.clone()));
+        let successors = nodes.iter()
+            .map(|n| Ok([id(&n.instruction.0)?, id(&n.instruction.1)?]))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { instructions, names, ids, successors })
+    }
+}
+
+impl Network {
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn id(&self, name: &str) -> Option<usize> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: usize) -> &str {
+        &self.names[id]
+    }
+
+    // the node reached from `id` following instruction number `step`
+    pub fn next(&self, id: usize, step: usize) -> usize {
+        self.successors[id][self.instructions[step % self.instructions.len()]]
+    }
+
+    pub fn nodes_where(&self, predicate: impl Fn(&str) -> bool) -> Vec<usize> {
+        (0..self.len()).filter(|id| predicate(&self.names[*id])).collect()
+    }
+
+    pub fn mark_where(&self, predicate: impl Fn(&str) -> bool) -> Vec<bool> {
+        self.names.iter().map(|n| predicate(n)).collect()
+    }
+}
+
+// which nodes a walk starts or ends on, written as "AAA" for an exact name,
+// "*Z" for a name suffix or "/^1.A$/" for a regex
+#[derive(Debug, Clone)]
+pub enum NodePattern {
+    Exact(String),
+    Suffix(String),
+    Regex(Regex),
+}
+
+impl FromStr for NodePattern {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(suffix) = s.strip_prefix('*') {
+            Ok(NodePattern::Suffix(suffix.to_string()))
+        } else if s.len() >= 2 && s.starts_with('/') && s.ends_with('/') {
+            Ok(NodePattern::Regex(Regex::new(&s[1..s.len() - 1])?))
+        } else {
+            Ok(NodePattern::Exact(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for NodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodePattern::Exact(name) => write!(f, "{}", name),
+            NodePattern::Suffix(suffix) => write!(f, "*{}", suffix),
+            NodePattern::Regex(re) => write!(f, "/{}/", re.as_str()),
+        }
+    }
+}
+
+impl NodePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NodePattern::Exact(n) => name == n,
+            NodePattern::Suffix(suffix) => name.ends_with(suffix.as_str()),
+            NodePattern::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GhostError {
+    MissingInstructions,
+    InvalidInstruction(char),
+    InvalidNode(String),
+    DuplicateNode(String),
+    UnknownNode(String),
+    // no node matches the start pattern
+    NoStart(String),
+    // the ghost starting at this node never reaches an end node
+    NeverArrives(String),
+    NoSimultaneousArrival,
+    // the combined cycle of the ghosts is too long to count in 64 bits
+    TooManySteps,
+}
+
+impl fmt::Display for GhostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GhostError::MissingInstructions => write!(f, "missing instructions"),
+            GhostError::InvalidInstruction(c) => write!(f, "invalid instruction {:?}", c),
+            GhostError::InvalidNode(line) => write!(f, "invalid node line {:?}", line),
+            GhostError::DuplicateNode(name) => write!(f, "node {} is defined twice", name),
+            GhostError::UnknownNode(name) => write!(f, "unknown node {}", name),
+            GhostError::NoStart(pattern) => write!(f, "no node matches {}", pattern),
+            GhostError::NeverArrives(name) => write!(f, "ghost starting at {} never reaches an end node", name),
+            GhostError::NoSimultaneousArrival => write!(f, "the ghosts are never all on end nodes at once"),
+            GhostError::TooManySteps => write!(f, "the ghosts' combined cycle does not fit in 64 bits"),
+        }
+    }
+}
+
+impl std::error::Error for GhostError {}
+
+// The walk of a single ghost. Its state is the node and the position in the
+// instructions, so it must repeat within nodes * instructions steps. The steps
+// it is on an end node are `hits` before the loop and `cycle_hits` + k * `cycle_len`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GhostCycle {
+    pub hits: Vec<u64>,
+    pub cycle_start: u64,
+    pub cycle_len: u64,
+    pub cycle_hits: Vec<u64>,
+}
+
+impl GhostCycle {
+
+    pub fn new(network: &Network, start: usize, ends: &[bool]) -> Self {
+        let steps = network.instructions.len();
+        // keyed by (node, instruction) packed into one index, so it grows with the walk only
+        let mut seen: HashMap<usize, u64> = HashMap::new();
+        let mut on_end = vec![];
+        let mut id = start;
+        for step in 0.. {
+            let state = id * steps + step as usize % steps;
+            if let Some(&first) = seen.get(&state) {
+                let (before, cycle): (Vec<u64>, Vec<u64>) = on_end.into_iter().partition(|s| *s < first);
+                return Self { hits: before, cycle_start: first, cycle_len: step - first, cycle_hits: cycle };
+            }
+            seen.insert(state, step);
+            if ends[id] {
+                on_end.push(step);
+            }
+            id = network.next(id, step as usize);
+        }
+        unreachable!()
+    }
+
+    // hits are in step order so they can be binary searched
+    pub fn is_hit(&self, step: u64) -> bool {
+        if step < self.cycle_start {
+            return self.hits.binary_search(&step).is_ok();
+        }
+        let offset = (step - self.cycle_start) % self.cycle_len;
+        self.cycle_hits.binary_search(&(self.cycle_start + offset)).is_ok()
+    }
+}
+
+// ghosts start on every node matching `start`, fewest steps until all are on a node matching `end`
+pub fn walk(network: &Network, start: &NodePattern, end: &NodePattern) -> Result<u64, GhostError> {
+    let starts = network.nodes_where(|name| start.matches(name));
+    if starts.is_empty() {
+        return Err(GhostError::NoStart(start.to_string()));
+    }
+    ghost_steps(network, &starts, &network.mark_where(|name| end.matches(name)))
+}
+
+// fewest steps (at least one) until every ghost is on an end node at the same time
+pub fn ghost_steps(network: &Network, starts: &[usize], ends: &[bool]) -> Result<u64, GhostError> {
+    let ghosts: Vec<_> = starts.iter().map(|s| GhostCycle::new(network, *s, ends)).collect();
+    if let Some((g, _)) = ghosts.iter().enumerate().find(|(_, g)| g.hits.is_empty() && g.cycle_hits.is_empty()) {
+        return Err(GhostError::NeverArrives(network.name(starts[g]).to_string()));
+    }
+
+    // before every ghost is looping, check each step
+    let looping = ghosts.iter().map(|g| g.cycle_start).max().unwrap_or(0).max(1);
+    if let Some(step) = (1..looping).find(|s| ghosts.iter().all(|g| g.is_hit(*s))) {
+        return Ok(step);
+    }
+
+    // every ghost is back where it was after `period` steps
+    let period = ghosts.iter().try_fold(1, |l, g| lcm(l, g.cycle_len)).ok_or(GhostError::TooManySteps)?;
+
+    // after that each ghost is on an end node at the steps `hit` mod `cycle_len`. Merge the
+    // ghosts one at a time with the chinese remainder theorem, fewest hits first, keeping the
+    // residues that still agree. Once there are too many, the rest are checked step by step.
+    let mut order: Vec<&GhostCycle> = ghosts.iter().collect();
+    order.sort_by_key(|g| g.cycle_hits.len());
+    let mut residues: Vec<(i128, i128)> = vec![(0, 1)];
+    let mut merged = 0;
+    for g in &order {
+        if residues.len() * g.cycle_hits.len() > MAX_RESIDUES {
+            break;
+        }
+        let mut next: Vec<(i128, i128)> = residues.iter()
+            .flat_map(|r| g.cycle_hits.iter().filter_map(move |h| crt(*r, (*h as i128, g.cycle_len as i128))))
+            .collect();
+        next.sort();
+        next.dedup();
+        residues = next;
+        merged += 1;
+    }
+    let rest = &order[merged..];
+
+    // all residues share the same modulus, which divides `period`, so scanning one
+    // period from `looping` in step order finds the first arrival if there is one
+    let modulus = residues.first().ok_or(GhostError::NoSimultaneousArrival)?.1;
+    let (looping, end) = (looping as i128, looping as i128 + period as i128);
+    let mut base = looping / modulus * modulus;
+    while base < end {
+        for step in residues.iter().map(|(r, _)| base + r).filter(|s| (looping..end).contains(s)) {
+            if rest.iter().all(|g| g.is_hit(step as u64)) {
+                return u64::try_from(step).map_err(|_| GhostError::TooManySteps);
+            }
+        }
+        base += modulus;
+    }
+    Err(GhostError::NoSimultaneousArrival)
+}
+
+// ghosts whose hits times the residues so far exceed this are not merged
+const MAX_RESIDUES: usize = 100_000;
+
+fn lcm(a: u64, b: u64) -> Option<u64> {
+    let (g, _, _) = extended_gcd(a as i128, b as i128);
+    (a / g as u64).checked_mul(b)
+}
+
+// (gcd(a, b), x, y) with a * x + b * y = gcd(a, b)
+fn extended_gcd(a: i128, b: i128) -> (i128, i128, i128) {
+    if b == 0 {
+        (a, 1, 0)
+    } else {
+        let (g, x, y) = extended_gcd(b, a % b);
+        (g, y, x - (a / b) * y)
+    }
+}
+
+// the combined (residue, modulus) of t = r1 mod m1 and t = r2 mod m2, None if they never agree
+fn crt((r1, m1): (i128, i128), (r2, m2): (i128, i128)) -> Option<(i128, i128)> {
+    let (g, p, _) = extended_gcd(m1, m2);
+    if (r2 - r1) % g != 0 {
+        return None;
+    }
+    let lcm = m1 / g * m2;
+    let k = ((r2 - r1) / g % (m2 / g) * p % (m2 / g)).rem_euclid(m2 / g);
+    Some(((r1 + m1 * k).rem_euclid(lcm), lcm))
+}
+
+/*
+ Solution command:
+    cargo run -- day8 data/day8.txt [start pattern] [end pattern]
+ where a pattern is a node name "AAA", a suffix "*A" or a regex "/^1.A$/"
+*/
+
 pub fn day8(args: &[String]) {
     println!("Day 8");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.len() != 1 && args.len() != 3 {
+        println!("Missing input file, optional start and end node patterns");
         return;
     }
     let filename = &args[0];
@@ -38,48 +328,32 @@ pub fn day8(args: &[String]) {
     let contents = fs::read_to_string(filename)
         .expect("Something went wrong reading the file");
 
-    let mut sections = contents.split("\n\n");
-    let intructions = sections.next().unwrap();
-    let nodes: Vec<Node> = sections.next().unwrap().lines()
-                                .map(| l | l.parse().unwrap()).collect();
-
-    println!("Part 1: {:?}", get_count(intructions, &nodes, "AAA", "ZZZ"));
-    println!("Part 2: {:?}", part2(intructions, &nodes));
-}
-
-fn get_count(instructions: &str, nodes: &Vec<Node>, start: &str, end: &str) -> Option<u64> {
-    let mut it = instructions.chars().cycle();
-    let mut name = start;
-    let mut count = 0;
-    loop {
-        let cur = nodes.iter().find(|&n| n.name == name);
-        if cur.is_none() { break; }
-        name = match it.next().unwrap() {
-            'L' => &cur.unwrap().instruction.0,
-            'R' => &cur.unwrap().instruction.1,
-            _ => unreachable!()
+    let network: Network = match contents.parse() {
+        Ok(network) => network,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut walks = vec![
+        ("Part 1".to_string(), "AAA".to_string(), "ZZZ".to_string()),
+        ("Part 2".to_string(), "*A".to_string(), "*Z".to_string()),
+    ];
+    if args.len() == 3 {
+        walks.push((format!("Walk {} to {}", args[1], args[2]), args[1].clone(), args[2].clone()));
+    }
+    for (name, start, end) in walks {
+        let (start, end): (NodePattern, NodePattern) = match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("{}: {}", name, e);
+                continue;
+            }
         };
-        count += 1;
-        if name == end { return Some(count) }
-        if count > 30_000 { break }  // max count limit
+        match walk(&network, &start, &end) {
+            Ok(steps) => println!("{}: {}", name, steps),
+            Err(e) => println!("{}: {}", name, e),
+        }
     }
-    None
-}
-
-fn nodes_ending_with(nodes: &Vec<Node>, value: &str) -> Vec<String> {
-    nodes.iter()
-        .filter(|&n| n.name.ends_with(value))
-        .map(|n| n.name.to_string()).collect()
 }
-
-fn part2(instructions: &str, nodes: &Vec<Node>) -> Option<u64> {
-    let starts: Vec<_> = nodes_ending_with(nodes, "A");
-    let ends: Vec<_> = nodes_ending_with(nodes, "Z");
-
-    // assume there is only one solution for getting from a given start to an end
-    let counts: Vec<_> = iproduct!(starts, ends)
-            .filter_map(|(s, e)| get_count(instructions, nodes, &s, &e))
-            .collect();
-    //println!("{:?}", counts);
-    lcmx(&counts)
-}
\ No newline at end of file
@@ -1,10 +1,14 @@
 use std::fs;
 
+/*
+ Solution command:
+    cargo run -- day9 data/day9.txt [step] [coefficients]
+*/
 
 pub fn day9(args: &[String]) {
     println!("Day 9");
-    if args.len() != 1 {
-        println!("Missing input file");
+    if args.is_empty() || args.len() > 3 || (args.len() == 3 && args[2] != "coefficients") {
+        println!("Missing input file, optional step and 'coefficients'");
         return;
     }
     let filename = &args[0];
@@ -12,33 +16,135 @@ pub fn day9(args: &[String]) {
     let contents = fs::read_to_string(filename)
         .expect("Something went wrong reading the file");
 
-    let histories: Vec<Vec<i32>> = contents.lines()
+    let histories: Vec<Vec<i128>> = contents.lines()
         .map(|l| l.split_whitespace().map(|d| d.parse().unwrap()).collect())
         .collect();
+    // None for histories whose difference table overflows
+    let fits: Vec<Option<Newton>> = histories.iter().map(|h| Newton::fit(h)).collect();
 
-    let part1: i32 = histories.iter().map(|h| part1(&h)).sum();
-    println!("{:?}", part1);
+    let part1 = sum_at(&fits, |h| h.len as i128);
+    println!("{}", show(part1));
 
-    let part2: i32 = histories.iter().map(|h| part2(&h)).sum();
-    println!("{:?}", part2);
+    let part2 = sum_at(&fits, |_| -1);
+    println!("{}", show(part2));
+
+    if args.len() >= 2 {
+        let step: i128 = match args[1].parse() {
+            Ok(step) => step,
+            Err(_) => {
+                println!("Step must be an integer");
+                return;
+            }
+        };
+        println!("Step {}: {}", step, show(sum_at(&fits, |_| step)));
+    }
+    if args.len() == 3 {
+        for fit in &fits {
+            match fit.as_ref().and_then(Newton::coefficients) {
+                Some(coefficients) => println!("{}", format_polynomial(&coefficients)),
+                None => println!("overflow"),
+            }
+        }
+    }
+}
+
+fn sum_at(fits: &[Option<Newton>], step: impl Fn(&Newton) -> i128) -> Option<i128> {
+    fits.iter().try_fold(0i128, |acc, f| acc.checked_add(f.as_ref()?.value_at(step(f.as_ref()?))?))
+}
+
+fn show(value: Option<i128>) -> String {
+    value.map_or("overflow".to_string(), |v| v.to_string())
+}
+
+// The history as a Newton forward difference polynomial: the value at step k
+// (the history being steps 0 to len - 1) is the sum of differences[j] * C(k, j).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Newton {
+    // first value of each row of the difference table, down to the last non zero row
+    pub differences: Vec<i128>,
+    pub len: usize,
 }
 
-fn extrapolate(history: &Vec<i32>) -> Vec<Vec<i32>> {
-    let mut histories = vec![history.clone()];
-    loop {
-        histories.push(histories.last().unwrap().windows(2).map(|v| v[1] - v[0]).collect());
-        if histories.last().unwrap().iter().all(|v| *v == 0) { break; }
+impl Newton {
+
+    // None if the difference table overflows
+    pub fn fit(history: &[i128]) -> Option<Self> {
+        let mut differences = vec![];
+        let mut row = history.to_vec();
+        while row.iter().any(|v| *v != 0) {
+            differences.push(row[0]);
+            row = row.windows(2).map(|v| v[1].checked_sub(v[0])).collect::<Option<_>>()?;
+        }
+        Some(Self { differences, len: history.len() })
+    }
+
+    // the extrapolated value at any step, negative steps going back before the history
+    pub fn value_at(&self, k: i128) -> Option<i128> {
+        let mut binomial: i128 = 1;
+        let mut total: i128 = 0;
+        for (j, d) in self.differences.iter().enumerate() {
+            if j > 0 {
+                // C(k, j) = C(k, j - 1) * (k - j + 1) / j divides exactly
+                binomial = binomial.checked_mul(k.checked_sub(j as i128 - 1)?)? / j as i128;
+            }
+            total = total.checked_add(d.checked_mul(binomial)?)?;
+        }
+        Some(total)
+    }
+
+    // coefficients of k^0, k^1, ... as reduced (numerator, denominator) fractions
+    pub fn coefficients(&self) -> Option<Vec<(i128, i128)>> {
+        let degree = self.differences.len();
+        // falling factorial k (k - 1) ... (k - j + 1) in powers of k, and j!
+        let mut falling: Vec<i128> = vec![1];
+        let mut factorial: i128 = 1;
+        let mut terms = vec![];
+        for (j, d) in self.differences.iter().enumerate() {
+            if j > 0 {
+                factorial = factorial.checked_mul(j as i128)?;
+                let mut next: Vec<i128> = vec![0; falling.len() + 1];
+                for (i, c) in falling.iter().enumerate() {
+                    next[i + 1] = next[i + 1].checked_add(*c)?;
+                    next[i] = next[i].checked_sub(c.checked_mul(j as i128 - 1)?)?;
+                }
+                falling = next;
+            }
+            terms.push((*d, falling.clone(), factorial));
+        }
+        let denominator = factorial;
+        let mut numerators = vec![0i128; degree.max(1)];
+        for (d, poly, f) in terms {
+            let scale = d.checked_mul(denominator / f)?;
+            for (i, c) in poly.iter().enumerate() {
+                numerators[i] = numerators[i].checked_add(c.checked_mul(scale)?)?;
+            }
+        }
+        Some(numerators.iter().map(|n| {
+            let g = gcd(n.abs(), denominator).max(1);
+            (n / g, denominator / g)
+        }).collect())
     }
-    histories
 }
 
-fn part1(history: &Vec<i32>) -> i32 {
-    let histories = extrapolate(history);
-    histories.iter().map(|h|h.last().unwrap()).sum()
+fn gcd(a: i128, b: i128) -> i128 {
+    if b == 0 { a } else { gcd(b, a % b) }
 }
 
-fn part2(history: &Vec<i32>) -> i32 {
-    let histories = extrapolate(history);
-    let firsts: Vec<_> = histories.iter().rev().map(|h| h.first().unwrap()).collect();
-    firsts.iter().fold(0, |acc, &f| f - acc)
-}
\ No newline at end of file
+fn format_polynomial(coefficients: &[(i128, i128)]) -> String {
+    let mut output = String::new();
+    for (i, (n, d)) in coefficients.iter().enumerate().rev().filter(|(_, (n, _))| *n != 0) {
+        let sign = if *n < 0 { "-" } else { "+" };
+        if output.is_empty() {
+            output.push_str(if *n < 0 { "-" } else { "" });
+        } else {
+            output.push_str(&format!(" {} ", sign));
+        }
+        let c = if *d == 1 { n.abs().to_string() } else { format!("{}/{}", n.abs(), d) };
+        output.push_str(&match i {
+            0 => c,
+            1 => format!("{} k", c),
+            _ => format!("{} k^{}", c, i),
+        });
+    }
+    if output.is_empty() { "0".to_string() } else { output }
+}